@@ -0,0 +1,686 @@
+// Lightweight locks whose state is a single atomic word.
+//
+// `AtomicLWLock` behaves like `LWLock`, but the exclusive flag, the shared count and the
+// wakeup flags are packed into one `AtomicUint`, so an uncontended acquisition or release
+// is a single atomic operation.  The spinlock only protects the queue of waiting Procs, and
+// is only taken when somebody has to wait.
+#![macro_escape]
+
+use lockdep::{mod, LockKind};
+use lwlock::{
+    mod,
+    LWLockMode,
+};
+use lwlock::LWLockMode::*;
+use process::{mod, ProcNumber};
+use s_lock::SpinLock;
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::AtomicUint;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+
+/// There are Procs in the wait queue.
+const LW_FLAG_HAS_WAITERS: uint = 1 << 30;
+/// Waiters have been woken but haven't retried yet, so don't wake any more.  This is the
+/// inverse of LWLock's `release_ok`, so that a free lock's state is all zeroes.
+const LW_FLAG_RELEASE_BLOCKED: uint = 1 << 29;
+
+const LW_VAL_EXCLUSIVE: uint = 1 << 24;
+const LW_VAL_SHARED: uint = 1;
+
+/// Bits that are set while anybody holds the lock.
+const LW_LOCK_MASK: uint = (1 << 25) - 1;
+
+#[doc(hidden)]
+pub struct WaitList {
+    /// head of list of waiting Procs
+    pub head: Option<ProcNumber>,
+    /// tail of list of waiting Procs
+    pub tail: Option<ProcNumber>,
+    // tail is undefined when head is NULL
+}
+
+impl WaitList {
+    /// Add a Proc to the end of the wait queue.  Caller must hold the mutex.
+    #[inline]
+    fn enqueue(&mut self, proc_: &'static process::Proc, mode: LWLockMode, tranche: u32) {
+        proc_.lw_waiting.set(true);
+        proc_.lw_wait_mode.set(mode);
+        proc_.lw_wait_tranche.set(tranche);
+        proc_.lw_wait_link.set(None);
+        match self.head {
+            Some(_) => {
+                // Note: we are assuming that tail was set correctly!
+                match self.tail {
+                    Some(tail) => tail.get().lw_wait_link.set(Some(proc_.pgprocno)),
+                    None => unreachable!(),
+                }
+            },
+            None => self.head = Some(proc_.pgprocno)
+        }
+        self.tail = Some(proc_.pgprocno);
+    }
+
+    /// Remove a Proc from anywhere in the wait queue.  Returns false if it wasn't queued.
+    /// Caller must hold the mutex.
+    fn remove(&mut self, proc_: &'static process::Proc) -> bool {
+        let mut prev: Option<ProcNumber> = None;
+        let mut cur = self.head;
+        loop {
+            match cur {
+                Some(p) if p == proc_.pgprocno => break,
+                Some(p) => {
+                    prev = cur;
+                    cur = p.get().lw_wait_link.get();
+                },
+                None => return false,
+            }
+        }
+        let next = proc_.lw_wait_link.get();
+        match prev {
+            Some(prev) => prev.get().lw_wait_link.set(next),
+            None => self.head = next,
+        }
+        if next.is_none() {
+            self.tail = prev;
+        }
+        proc_.lw_wait_link.set(None);
+        true
+    }
+}
+
+#[repr(C)]
+pub struct AtomicLWLock<T> {
+    #[doc(hidden)] pub state: AtomicUint,
+    #[doc(hidden)] pub waiters: SpinLock<(), WaitList>, // Protects queue of PROCs
+    #[doc(hidden)] pub tranche: u32,
+    #[doc(hidden)] pub data: UnsafeCell<T>,
+}
+
+macro_rules! atomic_lwlock_init(
+    ($data:expr) => (
+        atomic_lwlock_init!($data, ::lwlock::LWTRANCHE_MAIN)
+    );
+    ($data:expr, $tranche:expr) => (
+        ::atomic_lwlock::AtomicLWLock {
+            state: ::std::sync::atomic::INIT_ATOMIC_UINT,
+            waiters: spin_lock_init!((), ::atomic_lwlock::WaitList {
+                head: None,
+                tail: None,
+            }),
+            tranche: $tranche,
+            data: ::std::cell::UnsafeCell { value: $data },
+        }
+    )
+)
+
+impl<T> AtomicLWLock<T> where T: Send {
+    pub fn new(data: T) -> AtomicLWLock<T> {
+        atomic_lwlock_init!(data)
+    }
+
+    /// Put a newly created lock in a tranche other than the main one.
+    #[inline]
+    pub fn in_tranche(mut self, tranche: u32) -> AtomicLWLock<T> {
+        self.tranche = tranche;
+        self
+    }
+
+    /// The tranche this lock belongs to.
+    #[inline]
+    pub fn tranche(&self) -> u32 {
+        self.tranche
+    }
+
+    pub fn acquire(&self, mode: LWLockMode) -> bool {
+        self.acquire_at(mode, lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `acquire`, but tells the lock-order validator that the lock was acquired at
+    /// `file_line`.  See `lwlock_acquire!`.
+    pub fn acquire_at(&self, mode: LWLockMode, file_line: &(&'static str, uint)) -> bool {
+        self.acquire_common(mode, file_line)
+    }
+
+    /// Acquire the lock if it is free, but never wait for it.
+    ///
+    /// Returns true if the lock was acquired, in which case it must be released with
+    /// `release`.  If the lock is not immediately available, returns false without
+    /// touching the wait queue.
+    pub fn try_acquire(&self, mode: LWLockMode) -> bool {
+        self.try_acquire_at(mode, lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `try_acquire`, but tells the lock-order validator that the lock was acquired at
+    /// `file_line`.
+    pub fn try_acquire_at(&self, mode: LWLockMode, file_line: &(&'static str, uint)) -> bool {
+        assert!(mode != WaitUntilFree, "AtomicLWLock doesn't support LWLockMode::WaitUntilFree");
+
+        process::MY_PROC.with( |thread| {
+            // Ensure we will have room to remember the lock
+            lwlock::check_held_lwlocks(thread);
+
+            thread.hold_interrupts();
+
+            let acquired = self.attempt_lock(mode);
+            if acquired {
+                // Add lock to list of locks held by this backend
+                self.remember(thread, mode, file_line);
+            } else {
+                // Failed to get lock, so release interrupt holdoff
+                thread.resume_interrupts();
+            }
+
+            acquired
+        })
+    }
+
+    /// Like `lock_shared`, but returns `None` instead of waiting if the lock is not free.
+    #[inline]
+    pub fn try_lock_shared<'a>(&'a self) -> Option<AtomicLWLockSharedGuard<'a, T>> {
+        if self.try_acquire(Shared) {
+            Some(AtomicLWLockSharedGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Like `lock_exclusive`, but returns `None` instead of waiting if the lock is not free.
+    #[inline]
+    pub fn try_lock_exclusive<'a>(&'a self) -> Option<AtomicLWLockExclusiveGuard<'a, T>> {
+        if self.try_acquire(Exclusive) {
+            Some(AtomicLWLockExclusiveGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Acquire the lock in shared mode, returning a guard that releases it when dropped.
+    #[inline]
+    pub fn lock_shared<'a>(&'a self) -> AtomicLWLockSharedGuard<'a, T> {
+        self.lock_shared_at(lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `lock_shared`, but tells the lock-order validator that the lock was acquired at
+    /// `file_line`.  See `lwlock_shared!`.
+    #[inline]
+    pub fn lock_shared_at<'a>(&'a self,
+                              file_line: &(&'static str, uint)) -> AtomicLWLockSharedGuard<'a, T> {
+        self.acquire_common(Shared, file_line);
+        AtomicLWLockSharedGuard { lock: self }
+    }
+
+    /// Acquire the lock in exclusive mode, returning a guard that releases it when dropped.
+    #[inline]
+    pub fn lock_exclusive<'a>(&'a self) -> AtomicLWLockExclusiveGuard<'a, T> {
+        self.lock_exclusive_at(lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `lock_exclusive`, but tells the lock-order validator that the lock was acquired
+    /// at `file_line`.  See `lwlock_exclusive!`.
+    #[inline]
+    pub fn lock_exclusive_at<'a>(&'a self, file_line: &(&'static str, uint))
+                                 -> AtomicLWLockExclusiveGuard<'a, T> {
+        self.acquire_common(Exclusive, file_line);
+        AtomicLWLockExclusiveGuard { lock: self }
+    }
+
+    /// Try to take the lock with a single atomic operation.  Returns true if the lock was
+    /// acquired.
+    #[inline]
+    fn attempt_lock(&self, mode: LWLockMode) -> bool {
+        let mut old_state = self.state.load(Relaxed);
+        loop {
+            let desired_state = match mode {
+                Exclusive => {
+                    if old_state & LW_LOCK_MASK != 0 {
+                        return false
+                    }
+                    old_state + LW_VAL_EXCLUSIVE
+                },
+                Shared => {
+                    if old_state & LW_VAL_EXCLUSIVE != 0 {
+                        return false
+                    }
+                    old_state + LW_VAL_SHARED
+                },
+                WaitUntilFree => unreachable!(),
+            };
+            let state = self.state.compare_and_swap(old_state, desired_state, SeqCst);
+            if state == old_state {
+                return true
+            }
+            old_state = state;
+        }
+    }
+
+    fn acquire_common(&self, mode: LWLockMode, file_line: &(&'static str, uint)) -> bool {
+        assert!(mode != WaitUntilFree, "AtomicLWLock doesn't support LWLockMode::WaitUntilFree");
+
+        process::MY_PROC.with( |thread| {
+            let mut result = true;
+            let mut extra_waits = 0u32;
+
+            // Ensure we will have room to remember the lock
+            lwlock::check_held_lwlocks(thread);
+
+            // Make sure waiting can't deadlock
+            lockdep::check(LockKind::LWLock, &self.waiters.lockdep_id, mode == Exclusive, file_line);
+
+            // Lock out cancel/die interrupts until we exit the code section protected
+            // by the LWLock.  This ensures that interrupts will not interfere with
+            // manipulations of data structures in shared memory.
+            thread.hold_interrupts();
+
+            // Loop here to try to acquire lock after each time we are signaled by
+            // release.
+            loop {
+                if self.attempt_lock(mode) {
+                    break
+                }
+
+                // We couldn't get the lock on the first try.  We can't just queue ourselves
+                // and sleep, because by now the lock may have been released by someone who
+                // saw no waiters.  So queue ourselves first, and then try again: if the lock
+                // is still held, whoever holds it will see us when they release it.
+                self.queue_self(mode);
+
+                if self.attempt_lock(mode) {
+                    // Got the lock after all; take ourselves back off the queue.
+                    extra_waits += self.dequeue_self(thread);
+                    break
+                }
+
+                // Wait until awakened.
+                extra_waits += lwlock::wait_until_dequeued(thread);
+
+                // Retrying, allow release to release waiters again.
+                self.state.fetch_and(!LW_FLAG_RELEASE_BLOCKED, SeqCst);
+
+                // Now loop back and try to acquire lock again
+                result = false;
+            }
+
+            // Add lock to list of locks held by this backend
+            self.remember(thread, mode, file_line);
+
+            // Fix the process wait semaphore's count for any absorbed wakeups.
+            while extra_waits > 0 {
+                extra_waits -= 1;
+                thread.sem.release();
+            }
+
+            result
+        })
+    }
+
+    /// Add this thread to the wait queue.
+    fn queue_self(&self, mode: LWLockMode) {
+        spin_lock_acquire!(mut guard = self.waiters, {
+            // Set the flag before we try the lock again, so that any release after that
+            // attempt knows to wake us.
+            self.state.fetch_or(LW_FLAG_HAS_WAITERS, SeqCst);
+            guard.deref_mut().1.enqueue(process::my_proc(), mode, self.tranche);
+        })
+    }
+
+    /// Take this thread back off the wait queue, after it got the lock without sleeping.
+    ///
+    /// Returns the number of wakeups absorbed, as for `lwlock::wait_until_dequeued`.
+    fn dequeue_self(&self, thread: &process::Proc) -> u32 {
+        let found = spin_lock_acquire!(mut guard = self.waiters, {
+            let waiters = guard.deref_mut().1;
+            let found = waiters.remove(process::my_proc());
+            if waiters.head.is_none() {
+                self.state.fetch_and(!LW_FLAG_HAS_WAITERS, SeqCst);
+            }
+            found
+        });
+
+        if found {
+            thread.lw_waiting.set(false);
+            0
+        } else {
+            // Somebody else dequeued us and has or will wake us up.  They'll have blocked
+            // further wakeups until we retry, which we never will; unblock them.
+            self.state.fetch_and(!LW_FLAG_RELEASE_BLOCKED, SeqCst);
+
+            // Now wait for the scheduled wakeup, otherwise lw_waiting would get reset at some
+            // inconvenient point later.  Most of the time this will immediately return.
+            lwlock::wait_until_dequeued(thread)
+        }
+    }
+
+    /// Add this lock to the list of locks held by `thread`, acquired in `mode` at `file_line`.
+    #[inline]
+    fn remember(&self, thread: &process::Proc, mode: LWLockMode, file_line: &(&'static str, uint)) {
+        lwlock::remember_lwlock(thread, self as *const _ as *const (), release_held::<T>);
+        // The lock shares its lockdep id with the wait queue's spinlock.
+        lockdep::acquired(LockKind::LWLock, &self.waiters.lockdep_id, mode == Exclusive,
+                          file_line);
+    }
+
+    /// Release a previously acquired lock.
+    ///
+    /// Unsafe because the caller must actually hold the lock, in whatever mode it was
+    /// acquired.
+    pub unsafe fn release(&self) {
+        process::MY_PROC.with( |thread| {
+            // Remove lock from list of locks held.
+            lwlock::forget_lwlock(thread, self as *const _ as *const ());
+
+            self.release_internal();
+
+            // Now okay to allow cancel/die interrupts.
+            thread.resume_interrupts();
+        })
+    }
+
+    /// Release the lock without consulting the list of held locks.
+    unsafe fn release_internal(&self) {
+        lockdep::released(LockKind::LWLock, &self.waiters.lockdep_id);
+
+        // If the exclusive bit is set it must be ours, since nobody else can hold the lock at
+        // the same time as an exclusive holder.
+        let old_state = if self.state.load(Relaxed) & LW_VAL_EXCLUSIVE != 0 {
+            self.state.fetch_sub(LW_VAL_EXCLUSIVE, SeqCst) - LW_VAL_EXCLUSIVE
+        } else {
+            debug_assert!(self.state.load(Relaxed) & LW_LOCK_MASK != 0)
+            self.state.fetch_sub(LW_VAL_SHARED, SeqCst) - LW_VAL_SHARED
+        };
+
+        // Wake waiters only if we released the last hold, there are waiters, and nobody
+        // woken earlier is still on their way to retrying.
+        if old_state & (LW_LOCK_MASK | LW_FLAG_HAS_WAITERS | LW_FLAG_RELEASE_BLOCKED) ==
+           LW_FLAG_HAS_WAITERS {
+            self.wakeup();
+        }
+    }
+
+    /// Remove the Procs to be woken from the wait queue, and wake them.
+    fn wakeup(&self) {
+        let head = spin_lock_acquire!(mut guard = self.waiters, {
+            let waiters = guard.deref_mut().1;
+            let head = waiters.head;
+            match head {
+                Some(head) => {
+                    let mut proc_ = head.get();
+                    // If the front waiter wants exclusive lock, awaken him only. Otherwise
+                    // awaken as many waiters as want shared access.
+                    if proc_.lw_wait_mode.get() != Exclusive {
+                        loop {
+                            match proc_.lw_wait_next() {
+                                Some(next) if next.lw_wait_mode.get() != Exclusive => proc_ = next,
+                                _ => break,
+                            }
+                        }
+                    }
+                    // proc_ is now the last Proc to be released
+                    waiters.head = proc_.lw_wait_link.get();
+                    proc_.lw_wait_link.set(None);
+                    // prevent additional wakeups until retryer gets to run
+                    self.state.fetch_or(LW_FLAG_RELEASE_BLOCKED, SeqCst);
+                },
+                None => {},
+            }
+            if waiters.head.is_none() {
+                self.state.fetch_and(!LW_FLAG_HAS_WAITERS, SeqCst);
+            }
+            head
+        });
+
+        // Awaken any waiters I removed from the queue.
+        lwlock::wake_procs(head);
+    }
+}
+
+unsafe fn release_held<T>(lock: *const ()) where T: Send {
+    (*(lock as *const AtomicLWLock<T>)).release_internal()
+}
+
+#[must_use]
+pub struct AtomicLWLockSharedGuard<'a, T: 'a> {
+    lock: &'a AtomicLWLock<T>,
+}
+
+impl<'a, T> Deref<T> for AtomicLWLockSharedGuard<'a, T> {
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe {
+            &*self.lock.data.get()
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T> Drop for AtomicLWLockSharedGuard<'a, T> where T: Send {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.release();
+        }
+    }
+}
+
+#[must_use]
+pub struct AtomicLWLockExclusiveGuard<'a, T: 'a> {
+    lock: &'a AtomicLWLock<T>,
+}
+
+impl<'a, T> Deref<T> for AtomicLWLockExclusiveGuard<'a, T> {
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe {
+            &*self.lock.data.get()
+        }
+    }
+}
+
+impl<'a, T> DerefMut<T> for AtomicLWLockExclusiveGuard<'a, T> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *self.lock.data.get()
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T> Drop for AtomicLWLockExclusiveGuard<'a, T> where T: Send {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AtomicLWLock,
+        LW_FLAG_HAS_WAITERS,
+        LW_FLAG_RELEASE_BLOCKED,
+    };
+    use lwlock::{
+        mod,
+        LWLock,
+    };
+    use lwlock::LWLockMode::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUint, INIT_ATOMIC_UINT};
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::task;
+
+    const NUM_THREADS: uint = 8;
+    const NUM_ITERS: uint = 1000;
+
+    #[test]
+    fn test_acquire_release() {
+        let lock = AtomicLWLock::new(());
+        assert!(lock.acquire(Exclusive));
+        assert!(!lock.try_acquire(Shared));
+        unsafe { lock.release(); }
+        assert!(lock.acquire(Shared));
+        assert!(lock.try_acquire(Shared));
+        assert!(!lock.try_acquire(Exclusive));
+        unsafe {
+            lock.release();
+            lock.release();
+        }
+        assert_eq!(lock.state.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn test_guards() {
+        let lock = AtomicLWLock::new(vec![1u]);
+        {
+            let a = lock.lock_shared();
+            let b = lock.try_lock_shared().unwrap();
+            assert_eq!(a[0], b[0]);
+            assert!(lock.try_lock_exclusive().is_none());
+        }
+        lock.lock_exclusive().push(2);
+        assert_eq!(*lock.lock_shared(), vec![1, 2]);
+        assert_eq!(lock.state.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn test_release_all() {
+        let a = AtomicLWLock::new(());
+        let b = LWLock::new(());
+        a.acquire(Exclusive);
+        b.acquire(Exclusive);
+        lwlock::release_all();
+        assert!(a.try_lock_exclusive().is_some());
+        assert!(b.try_lock_exclusive().is_some());
+    }
+
+    #[test]
+    fn test_mixed_contended() {
+        static LOCK: AtomicLWLock<uint> = atomic_lwlock_init!(0);
+        static EXCLUSIVE_HOLDERS: AtomicUint = INIT_ATOMIC_UINT;
+        static SHARED_HOLDERS: AtomicUint = INIT_ATOMIC_UINT;
+
+        let (tx, rx) = channel();
+        for i in range(0, NUM_THREADS) {
+            let tx = tx.clone();
+            spawn(proc() {
+                for j in range(0, NUM_ITERS) {
+                    if (i + j) % 4 == 0 {
+                        let mut guard = LOCK.lock_exclusive();
+                        assert_eq!(EXCLUSIVE_HOLDERS.fetch_add(1, SeqCst), 0);
+                        assert_eq!(SHARED_HOLDERS.load(SeqCst), 0);
+                        *guard += 1;
+                        task::deschedule();
+                        EXCLUSIVE_HOLDERS.fetch_sub(1, SeqCst);
+                    } else {
+                        let _guard = LOCK.lock_shared();
+                        SHARED_HOLDERS.fetch_add(1, SeqCst);
+                        assert_eq!(EXCLUSIVE_HOLDERS.load(SeqCst), 0);
+                        task::deschedule();
+                        SHARED_HOLDERS.fetch_sub(1, SeqCst);
+                    }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert_eq!(*LOCK.lock_shared(), NUM_THREADS * NUM_ITERS / 4);
+        // Nobody is left waiting, and nobody is still due to retry.
+        assert_eq!(LOCK.state.load(SeqCst) & (LW_FLAG_HAS_WAITERS | LW_FLAG_RELEASE_BLOCKED), 0);
+        spin_lock_acquire!(guard = LOCK.waiters, {
+            assert!(guard.deref().1.head.is_none());
+        })
+    }
+
+    /// Number of background threads taking the lock in shared mode in the contended benches.
+    const NUM_READERS: uint = 3;
+
+    #[bench]
+    fn bench_lwlock_shared_uncontended(b: &mut ::test::Bencher) {
+        let lock = LWLock::new(());
+        b.iter( || {
+            let _guard = lock.lock_shared();
+        })
+    }
+
+    #[bench]
+    fn bench_atomic_lwlock_shared_uncontended(b: &mut ::test::Bencher) {
+        let lock = AtomicLWLock::new(());
+        b.iter( || {
+            let _guard = lock.lock_shared();
+        })
+    }
+
+    #[bench]
+    fn bench_lwlock_exclusive_uncontended(b: &mut ::test::Bencher) {
+        let lock = LWLock::new(());
+        b.iter( || {
+            let _guard = lock.lock_exclusive();
+        })
+    }
+
+    #[bench]
+    fn bench_atomic_lwlock_exclusive_uncontended(b: &mut ::test::Bencher) {
+        let lock = AtomicLWLock::new(());
+        b.iter( || {
+            let _guard = lock.lock_exclusive();
+        })
+    }
+
+    /// The part of the LWLock interface the contended benches need.
+    trait SharedLock {
+        fn touch_shared(&self);
+    }
+
+    impl SharedLock for LWLock<()> {
+        fn touch_shared(&self) {
+            let _guard = self.lock_shared();
+        }
+    }
+
+    impl SharedLock for AtomicLWLock<()> {
+        fn touch_shared(&self) {
+            let _guard = self.lock_shared();
+        }
+    }
+
+    /// Time shared acquisitions of a lock that `NUM_READERS` other threads are taking in
+    /// shared mode too.
+    fn bench_shared_contended<L>(b: &mut ::test::Bencher, lock: L)
+                                 where L: SharedLock + Send + Sync {
+        let lock = Arc::new(lock);
+        let done = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_READERS) {
+            let (lock, done, tx) = (lock.clone(), done.clone(), tx.clone());
+            spawn(proc() {
+                while !done.load(SeqCst) {
+                    lock.touch_shared();
+                }
+                tx.send(());
+            });
+        }
+        b.iter( || {
+            lock.touch_shared();
+        });
+        done.store(true, SeqCst);
+        for _ in range(0, NUM_READERS) {
+            rx.recv();
+        }
+    }
+
+    #[bench]
+    fn bench_lwlock_shared_contended(b: &mut ::test::Bencher) {
+        bench_shared_contended(b, LWLock::new(()));
+    }
+
+    #[bench]
+    fn bench_atomic_lwlock_shared_contended(b: &mut ::test::Bencher) {
+        bench_shared_contended(b, AtomicLWLock::new(()));
+    }
+}
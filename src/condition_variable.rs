@@ -0,0 +1,373 @@
+// Condition variables, for sleeping until some shared state changes.
+//
+// A thread that wants to wait for a condition prepares to sleep on the condition variable,
+// then sleeps until the condition holds, rechecking it each time it wakes:
+//
+//     let sleep = cv.prepare_to_sleep();
+//     while !condition_holds() {
+//         sleep.sleep();
+//     }
+//     drop(sleep);
+//
+// Whoever makes the condition true then calls `signal` or `broadcast`.  Because the sleeper is
+// on the wait list from the moment it prepares, a signal sent after it checked the condition
+// but before it slept still wakes it.
+//
+// As with LWLocks, waiting threads are queued through their Procs, linked by ProcNumber; they
+// sleep on their Proc's latch, so interrupts wake them too.
+#![macro_escape]
+
+use latch::WL_LATCH_SET;
+use pg_sema;
+use process::{mod, Proc, ProcNumber};
+use s_lock::SpinLock;
+
+use std::time::Duration;
+use time;
+
+#[doc(hidden)]
+pub struct ProcList {
+    /// head of list of waiting Procs
+    pub head: Option<ProcNumber>,
+    /// tail of list of waiting Procs
+    pub tail: Option<ProcNumber>,
+    // tail is undefined when head is NULL
+}
+
+impl ProcList {
+    /// Add a Proc to the end of the list.  Caller must hold the mutex.
+    fn push_tail(&mut self, proc_: &Proc) {
+        proc_.cv_waiting.set(true);
+        proc_.cv_wait_link.set(None);
+        match self.head {
+            Some(_) => {
+                match self.tail {
+                    Some(tail) => tail.get().cv_wait_link.set(Some(proc_.pgprocno)),
+                    None => unreachable!(),
+                }
+            },
+            None => self.head = Some(proc_.pgprocno)
+        }
+        self.tail = Some(proc_.pgprocno);
+    }
+
+    /// Take the first Proc off the list.  Caller must hold the mutex.
+    fn pop_head(&mut self) -> Option<&'static Proc> {
+        self.head.map( |head| {
+            let proc_ = head.get();
+            self.head = proc_.cv_wait_link.get();
+            proc_.cv_wait_link.set(None);
+            proc_.cv_waiting.set(false);
+            proc_
+        })
+    }
+
+    /// Take a Proc off the list, wherever it is.  Returns false if it wasn't there.  Caller
+    /// must hold the mutex.
+    fn remove(&mut self, proc_: &Proc) -> bool {
+        if !proc_.cv_waiting.get() {
+            return false
+        }
+        let mut prev: Option<ProcNumber> = None;
+        let mut cur = self.head;
+        loop {
+            match cur {
+                Some(p) if p == proc_.pgprocno => break,
+                Some(p) => {
+                    prev = cur;
+                    cur = p.get().cv_wait_link.get();
+                },
+                None => return false,
+            }
+        }
+        let next = proc_.cv_wait_link.get();
+        match prev {
+            Some(prev) => prev.get().cv_wait_link.set(next),
+            None => self.head = next,
+        }
+        if next.is_none() {
+            self.tail = prev;
+        }
+        proc_.cv_wait_link.set(None);
+        proc_.cv_waiting.set(false);
+        true
+    }
+}
+
+pub struct ConditionVariable {
+    #[doc(hidden)] pub waiters: SpinLock<(), ProcList>, // Protects the wait list
+}
+
+macro_rules! condition_variable_init(
+    () => (
+        ::condition_variable::ConditionVariable {
+            waiters: spin_lock_init!((), ::condition_variable::ProcList {
+                head: None,
+                tail: None,
+            }),
+        }
+    )
+)
+
+/// Set while this thread is prepared to sleep on a condition variable.  A Proc can only be on
+/// one wait list at a time.
+#[thread_local]
+static mut SLEEP_PREPARED: bool = false;
+
+impl ConditionVariable {
+    pub fn new() -> ConditionVariable {
+        condition_variable_init!()
+    }
+
+    /// Add this thread to the wait list, so that `signal` and `broadcast` wake it from here on.
+    /// The thread is taken off the list when the returned `ConditionVariableSleep` is dropped.
+    ///
+    /// A thread can only prepare to sleep on one condition variable at a time.
+    pub fn prepare_to_sleep<'a>(&'a self) -> ConditionVariableSleep<'a> {
+        unsafe {
+            assert!(!SLEEP_PREPARED, "already prepared to sleep on a condition variable");
+            SLEEP_PREPARED = true;
+        }
+        let proc_ = process::my_proc();
+        // Reset the latch before adding ourselves to the list, so that we don't miss a
+        // wakeup that comes straight away.
+        proc_.latch.reset();
+        spin_lock_acquire!(mut guard = self.waiters, {
+            guard.deref_mut().1.push_tail(proc_);
+        });
+        ConditionVariableSleep { cv: self, proc_: proc_ }
+    }
+
+    /// Wake the thread that has been waiting longest, if any.  Returns true if there was one.
+    pub fn signal(&self) -> bool {
+        let proc_ = spin_lock_acquire!(mut guard = self.waiters, {
+            guard.deref_mut().1.pop_head()
+        });
+        match proc_ {
+            Some(proc_) => {
+                proc_.latch.set();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread.  Returns how many there were.
+    pub fn broadcast(&self) -> uint {
+        // Woken threads put themselves back on the list, so take everyone off it at once.
+        // That means setting their latches while we still hold the mutex, since after that
+        // the links we would follow may be reused; setting a latch is cheap.
+        spin_lock_acquire!(mut guard = self.waiters, {
+            let waiters = guard.deref_mut().1;
+            let mut woken = 0;
+            loop {
+                match waiters.pop_head() {
+                    Some(proc_) => {
+                        proc_.latch.set();
+                        woken += 1;
+                    },
+                    None => break,
+                }
+            }
+            woken
+        })
+    }
+}
+
+/// A thread prepared to sleep on a condition variable.  See `ConditionVariable`.
+pub struct ConditionVariableSleep<'a> {
+    cv: &'a ConditionVariable,
+    proc_: &'static Proc,
+}
+
+impl<'a> ConditionVariableSleep<'a> {
+    /// Sleep until the condition variable is signalled.  The caller should then recheck its
+    /// condition, and sleep again if it still doesn't hold; we stay on the wait list in the
+    /// meantime, so nothing is missed.  Wakeups can also be spurious.
+    ///
+    /// Interrupts are processed while sleeping, so this can panic.
+    pub fn sleep(&self) {
+        self.wait(None);
+    }
+
+    /// Like `sleep`, but give up after `timeout`.  Returns false if we timed out.
+    pub fn sleep_timeout(&self, timeout: Duration) -> bool {
+        self.wait(Some(timeout))
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(pg_sema::deadline_after);
+        let mut cur_timeout = timeout;
+        loop {
+            let events = self.proc_.latch.wait(WL_LATCH_SET, cur_timeout);
+            self.proc_.latch.reset();
+
+            // If we were taken off the wait list, we were signalled, so return and let the
+            // caller check its condition.  Put ourselves back on the list first, so that we
+            // don't miss a signal that comes while they do.  If we're still on the list,
+            // something else set our latch; keep sleeping.
+            let signalled = spin_lock_acquire!(mut guard = self.cv.waiters, {
+                if self.proc_.cv_waiting.get() {
+                    false
+                } else {
+                    guard.deref_mut().1.push_tail(self.proc_);
+                    true
+                }
+            });
+
+            self.proc_.check_for_interrupts();
+
+            if signalled {
+                return true
+            }
+            match deadline {
+                Some(deadline) => {
+                    let now = time::precise_time_ns();
+                    if events & WL_LATCH_SET == 0 || now >= deadline {
+                        return false
+                    }
+                    cur_timeout = Some(Duration::nanoseconds((deadline - now) as i64));
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for ConditionVariableSleep<'a> {
+    /// Stop waiting: take this thread off the wait list, if it is still there.
+    fn drop(&mut self) {
+        let proc_ = self.proc_;
+        let removed = spin_lock_acquire!(mut guard = self.cv.waiters, {
+            guard.deref_mut().1.remove(proc_)
+        });
+        // If a signal took us off the list, pass it on, so that it isn't wasted on a thread
+        // that no longer wants it.  At worst the next thread wakes up for nothing and
+        // rechecks its condition.
+        if !removed {
+            self.cv.signal();
+        }
+        unsafe {
+            SLEEP_PREPARED = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConditionVariable;
+    use process::{mod, ProcNumber, ProcSignal};
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, INIT_ATOMIC_BOOL};
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::task;
+    use std::time::Duration;
+
+    fn queue_len(cv: &ConditionVariable) -> uint {
+        spin_lock_acquire!(guard = cv.waiters, {
+            let mut queued = 0u;
+            let mut proc_ = guard.deref().1.head;
+            loop {
+                match proc_ {
+                    Some(p) => { queued += 1; proc_ = p.get().cv_wait_link.get(); },
+                    None => break,
+                }
+            }
+            queued
+        })
+    }
+
+    #[test]
+    fn test_prepare_cancel() {
+        let cv = ConditionVariable::new();
+        {
+            let _sleep = cv.prepare_to_sleep();
+            assert_eq!(queue_len(&cv), 1);
+        }
+        assert_eq!(queue_len(&cv), 0);
+        assert!(!cv.signal());
+        assert_eq!(cv.broadcast(), 0);
+    }
+
+    #[test]
+    fn test_signal() {
+        static CV: ConditionVariable = condition_variable_init!();
+        static DONE: AtomicBool = INIT_ATOMIC_BOOL;
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let sleep = CV.prepare_to_sleep();
+            tx.send(());
+            while !DONE.load(SeqCst) {
+                sleep.sleep();
+            }
+            drop(sleep);
+            tx.send(());
+        });
+        rx.recv();
+        DONE.store(true, SeqCst);
+        assert!(CV.signal());
+        rx.recv();
+        assert_eq!(queue_len(&CV), 0);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let cv = Arc::new(ConditionVariable::new());
+        let state = Arc::new(Mutex::new(false));
+        let (tx, rx) = channel();
+        for _ in range(0u, 4) {
+            let (cv, state, tx) = (cv.clone(), state.clone(), tx.clone());
+            spawn(proc() {
+                let sleep = cv.prepare_to_sleep();
+                tx.send(());
+                while !*state.lock() {
+                    sleep.sleep();
+                }
+                drop(sleep);
+                tx.send(());
+            });
+        }
+        for _ in range(0u, 4) {
+            rx.recv();
+        }
+        *state.lock() = true;
+        cv.broadcast();
+        for _ in range(0u, 4) {
+            rx.recv();
+        }
+        assert_eq!(queue_len(&*cv), 0);
+    }
+
+    #[test]
+    fn test_sleep_timeout() {
+        let cv = ConditionVariable::new();
+        let sleep = cv.prepare_to_sleep();
+        assert!(!sleep.sleep_timeout(Duration::milliseconds(10)));
+        // We're still on the list until we stop waiting.
+        assert_eq!(queue_len(&cv), 1);
+        drop(sleep);
+        assert_eq!(queue_len(&cv), 0);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // A cancelled sleeper comes off the wait list as it unwinds.
+        let cv = Arc::new(ConditionVariable::new());
+        let cv_ = cv.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let waiter: ProcNumber = rx.recv();
+            waiter.get().signal(ProcSignal::Cancel);
+        });
+        assert!(task::try(proc() {
+            let sleep = cv_.prepare_to_sleep();
+            tx.send(process::my_proc().pgprocno);
+            loop {
+                sleep.sleep();
+            }
+        }).is_err());
+        assert_eq!(queue_len(&*cv), 0);
+    }
+}
@@ -0,0 +1,540 @@
+// Deadlock detection for heavyweight locks (PostgreSQL's deadlock.c).
+//
+// We don't check for deadlock when a thread starts waiting for a lock, only once it has waited
+// `deadlock_timeout`, on the theory that most waits are short and checking is expensive: it
+// locks every partition of the lock table while it looks.
+//
+// The check looks for a cycle through the waiting thread in the wait-for graph.  A waiting Proc
+// has a hard edge to each Proc that holds the lock in a conflicting mode, and a soft edge to
+// each Proc ahead of it in the lock's wait queue that wants a conflicting mode.  A cycle of
+// hard edges is a real deadlock.  A cycle with soft edges in it might be broken by reordering
+// wait queues, so that the waiter at one end of a soft edge goes in front of the blocker; so we
+// search for a set of such reorderings (constraints) under which no cycle through the waiter,
+// or through any Proc the reorderings move, remains.  If we find one, we apply it and wake
+// whoever can now have their lock; if not, the thread that ran the check gives up its wait and
+// reports the cycle.
+
+use lock::{LOCK_CONFLICTS, Lock, LockMode, LockTable, LockTag, lock_partition, lockbit_on};
+use lwlock::LWLockExclusiveGuard;
+use process::{ProcNumber, MAX_BACKENDS};
+
+use std::i32;
+use std::time::Duration;
+
+/// The default for `deadlock_timeout`, in milliseconds.
+pub const DEFAULT_DEADLOCK_TIMEOUT_MS: u32 = 1000;
+
+#[thread_local] static mut DEADLOCK_TIMEOUT_MS: u32 = DEFAULT_DEADLOCK_TIMEOUT_MS;
+
+/// How long this thread waits for a heavyweight lock before checking for deadlock.
+pub fn deadlock_timeout() -> Duration {
+    Duration::milliseconds(unsafe { DEADLOCK_TIMEOUT_MS } as i64)
+}
+
+/// Set how long this thread waits for a heavyweight lock before checking for deadlock.  Since
+/// checking holds up everyone else's locking, this should be longer than most lock waits.
+pub fn set_deadlock_timeout(timeout: Duration) {
+    let timeout_ms = timeout.num_milliseconds();
+    assert!(timeout_ms > 0 && timeout_ms <= i32::MAX as i64,
+            "invalid deadlock timeout {}", timeout);
+    unsafe {
+        DEADLOCK_TIMEOUT_MS = timeout_ms as u32;
+    }
+}
+
+/// Limits on how far the search for queue reorderings goes.  Past these, we give up and call
+/// it a hard deadlock.
+const MAX_CUR_CONSTRAINTS: uint = MAX_BACKENDS;
+const MAX_POSSIBLE_CONSTRAINTS: uint = MAX_BACKENDS * 4;
+
+/// An edge of the wait-for graph: `waiter` is waiting for `lock`, and is blocked by `blocker`.
+/// A soft edge, added to the current configuration, becomes a constraint that `waiter` go
+/// ahead of `blocker` in the lock's wait queue.
+#[deriving(Clone)]
+struct Edge {
+    waiter: ProcNumber,
+    blocker: ProcNumber,
+    lock: LockTag,
+}
+
+/// A new order for a lock's wait queue, satisfying the current constraints.
+struct WaitOrder {
+    lock: LockTag,
+    procs: Vec<ProcNumber>,
+}
+
+/// One step of a deadlock cycle: `proc_` waits for `locktag` in `lockmode`, and is blocked by
+/// the Proc of the next step.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct DeadlockInfo {
+    pub locktag: LockTag,
+    pub lockmode: LockMode,
+    pub proc_: ProcNumber,
+}
+
+/// The result of `deadlock_check`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum DeadlockState {
+    /// no deadlock
+    NoDeadlock,
+    /// a deadlock, which we broke by reordering wait queues
+    SoftDeadlock,
+    /// a deadlock that can't be broken; the cycle, starting from the Proc we checked
+    HardDeadlock(Vec<DeadlockInfo>),
+}
+
+/// The lock table partitions, all locked by the thread running the check.
+type LockTables = [LWLockExclusiveGuard<'static, LockTable>];
+
+fn get_lock<'a>(tables: &'a LockTables, tag: &LockTag) -> &'a Lock {
+    tables[lock_partition(tag)].get(tag).expect("lock table corrupted")
+}
+
+struct DeadlockChecker<'a> {
+    tables: &'a LockTables,
+    /// Procs visited by the current `find_lock_cycle`, in the order we reached them
+    visited: Vec<ProcNumber>,
+    /// the cycle found by the last successful `find_lock_cycle`
+    details: Vec<DeadlockInfo>,
+    /// soft edges we are trying to reverse
+    cur_constraints: Vec<Edge>,
+    /// soft edges found, but not yet tried, by the searches in progress
+    n_possible_constraints: uint,
+    /// wait queue orders implementing `cur_constraints`, as computed by `expand_constraints`
+    wait_orders: Vec<WaitOrder>,
+}
+
+/// Check whether `proc_`, which must be waiting for a lock, is part of a deadlock.  If the
+/// deadlock can be broken by reordering wait queues, do that, and wake any Procs that can now
+/// have their locks.  Caller must hold every lock table partition.
+pub fn deadlock_check(proc_: ProcNumber, tables: &mut LockTables) -> DeadlockState {
+    let wait_orders = {
+        let mut checker = DeadlockChecker {
+            tables: &*tables,
+            visited: Vec::new(),
+            details: Vec::new(),
+            cur_constraints: Vec::new(),
+            n_possible_constraints: 0,
+            wait_orders: Vec::new(),
+        };
+        if checker.deadlock_check_recurse(proc_) {
+            // Find the cycle once more, without any reordering, to report it.
+            checker.wait_orders.clear();
+            let mut soft_edges = Vec::new();
+            if !checker.find_lock_cycle(proc_, &mut soft_edges) {
+                panic!("deadlock seems to have disappeared");
+            }
+            return DeadlockState::HardDeadlock(checker.details)
+        }
+        checker.wait_orders
+    };
+
+    if wait_orders.is_empty() {
+        return DeadlockState::NoDeadlock
+    }
+    for order in wait_orders.into_iter() {
+        let lock = tables[lock_partition(&order.lock)].get_mut(&order.lock)
+                                                      .expect("lock table corrupted");
+        debug_assert_eq!(lock.wait_procs.len(), order.procs.len());
+        lock.wait_procs = order.procs;
+        lock.wake_waiters();
+    }
+    DeadlockState::SoftDeadlock
+}
+
+/// The message for a hard deadlock.
+pub fn deadlock_report(cycle: &[DeadlockInfo]) -> String {
+    let mut report = String::from_str("deadlock detected");
+    for (i, info) in cycle.iter().enumerate() {
+        let next = &cycle[(i + 1) % cycle.len()];
+        report.push_str(format!("\nProc {} waits for {} on {}; blocked by Proc {}.",
+                                info.proc_.to_uint(), info.lockmode, info.locktag,
+                                next.proc_.to_uint()).as_slice());
+    }
+    report
+}
+
+/// Find the position of a Proc in a wait queue.
+fn queue_position(queue: &[ProcNumber], proc_: ProcNumber) -> uint {
+    queue.iter().position( |&p| p == proc_).expect("deadlock constraint not in wait queue")
+}
+
+/// Order the lock's wait queue so that every constraint on it is satisfied, moving Procs as
+/// little as possible.  Returns None if the constraints contradict each other.
+fn topo_sort(lock: &Lock, tag: &LockTag, constraints: &[Edge]) -> Option<Vec<ProcNumber>> {
+    let queue_size = lock.wait_procs.len();
+    let mut topo_procs: Vec<Option<ProcNumber>> = lock.wait_procs.iter()
+                                                                 .map( |&p| Some(p))
+                                                                 .collect();
+    // For each Proc, how many others it must still go ahead of, and which must go ahead of
+    // it.
+    let mut before_constraints = Vec::from_elem(queue_size, 0u);
+    let mut after_constraints: Vec<Vec<uint>> = Vec::from_fn(queue_size, |_| Vec::new());
+    for constraint in constraints.iter().filter( |c| c.lock == *tag) {
+        let waiter = queue_position(lock.wait_procs.as_slice(), constraint.waiter);
+        let blocker = queue_position(lock.wait_procs.as_slice(), constraint.blocker);
+        before_constraints[waiter] += 1;
+        after_constraints[blocker].push(waiter);
+    }
+
+    // Fill the new queue from the back, each time taking the last Proc that doesn't have to go
+    // ahead of anything left, so that Procs keep their old order where they can.
+    let mut ordering = Vec::from_elem(queue_size, ProcNumber(0));
+    let mut last = queue_size;
+    for i in range(0, queue_size).rev() {
+        while topo_procs[last - 1].is_none() {
+            last -= 1;
+        }
+        let j = match range(0, last).rev().find( |&j| {
+            topo_procs[j].is_some() && before_constraints[j] == 0
+        }) {
+            Some(j) => j,
+            None => return None,
+        };
+        ordering[i] = topo_procs[j].take().unwrap();
+        for &waiter in after_constraints[j].iter() {
+            before_constraints[waiter] -= 1;
+        }
+    }
+    Some(ordering)
+}
+
+impl<'a> DeadlockChecker<'a> {
+    /// Search for a set of constraints under which there is no cycle through `proc_`.  Returns
+    /// true if there is none: a hard deadlock.  Otherwise `wait_orders` holds the reordered
+    /// queues.
+    fn deadlock_check_recurse(&mut self, proc_: ProcNumber) -> bool {
+        let edges = match self.test_configuration(proc_) {
+            Some(edges) => edges,
+            // hard deadlock, whatever we reorder
+            None => return true,
+        };
+        if edges.is_empty() {
+            // no cycles left
+            return false
+        }
+        if self.cur_constraints.len() >= MAX_CUR_CONSTRAINTS {
+            return true
+        }
+        let n_edges = edges.len();
+        self.n_possible_constraints += n_edges;
+        // Try breaking the cycle at each of its soft edges in turn.
+        for edge in edges.into_iter() {
+            self.cur_constraints.push(edge);
+            if !self.deadlock_check_recurse(proc_) {
+                return false
+            }
+            self.cur_constraints.pop();
+        }
+        self.n_possible_constraints -= n_edges;
+        true
+    }
+
+    /// Check the current constraints for cycles through `proc_` or any Proc they mention.
+    /// Returns None if there is a hard cycle, or the constraints contradict each other;
+    /// otherwise, the soft edges of a remaining cycle, if any.
+    fn test_configuration(&mut self, proc_: ProcNumber) -> Option<Vec<Edge>> {
+        if self.n_possible_constraints + MAX_BACKENDS > MAX_POSSIBLE_CONSTRAINTS {
+            return None
+        }
+        if !self.expand_constraints() {
+            return None
+        }
+        // Check `proc_` last, so that if it is in a soft cycle we deal with that first.
+        let mut check = Vec::with_capacity(self.cur_constraints.len() * 2 + 1);
+        for constraint in self.cur_constraints.iter() {
+            check.push(constraint.waiter);
+            check.push(constraint.blocker);
+        }
+        check.push(proc_);
+
+        let mut soft_found = Vec::new();
+        let mut soft_edges = Vec::new();
+        for &start in check.iter() {
+            if self.find_lock_cycle(start, &mut soft_edges) {
+                if soft_edges.is_empty() {
+                    return None
+                }
+                soft_found = soft_edges.clone();
+            }
+        }
+        Some(soft_found)
+    }
+
+    /// Work out the wait queue orders that satisfy the current constraints.  Returns false if
+    /// some queue can't satisfy them all.
+    fn expand_constraints(&mut self) -> bool {
+        self.wait_orders.clear();
+        // The newest constraint is the only one that can make things inconsistent, so start
+        // there.
+        for i in range(0, self.cur_constraints.len()).rev() {
+            let tag = self.cur_constraints[i].lock.clone();
+            if self.wait_orders.iter().any( |order| order.lock == tag) {
+                continue
+            }
+            let lock = get_lock(self.tables, &tag);
+            match topo_sort(lock, &tag, self.cur_constraints.slice_to(i + 1)) {
+                Some(procs) => self.wait_orders.push(WaitOrder { lock: tag, procs: procs }),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Look for a cycle in the wait-for graph through `start`, using the wait queue orders in
+    /// `wait_orders` where there are any.  If one is found, its soft edges are left in
+    /// `soft_edges`, and its steps in `details`.
+    fn find_lock_cycle(&mut self, start: ProcNumber, soft_edges: &mut Vec<Edge>) -> bool {
+        self.visited.clear();
+        self.details.clear();
+        soft_edges.clear();
+        if self.find_lock_cycle_recurse(start, soft_edges) {
+            // The steps were added as we unwound, so they're backwards.
+            self.details.reverse();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn find_lock_cycle_recurse(&mut self, check: ProcNumber, soft_edges: &mut Vec<Edge>)
+                               -> bool {
+        match self.visited.iter().position( |&p| p == check) {
+            // We're back where we started.
+            Some(0) => return true,
+            // A cycle, but not through the start; its own members will find it.
+            Some(_) => return false,
+            None => self.visited.push(check),
+        }
+
+        let check_proc = check.get();
+        let tag = match check_proc.wait_lock.get() {
+            Some(tag) => tag,
+            // not waiting, so no edges out
+            None => return false,
+        };
+        let mode = check_proc.wait_lock_mode.get();
+        let conflict_mask = LOCK_CONFLICTS[mode as uint];
+        let tables = self.tables;
+        let lock = get_lock(tables, &tag);
+
+        // Hard edges, to Procs holding the lock in a conflicting mode.
+        for proc_lock in lock.proc_locks.iter() {
+            if proc_lock.proc_ != check && proc_lock.hold_mask & conflict_mask != 0 &&
+               self.find_lock_cycle_recurse(proc_lock.proc_, soft_edges) {
+                self.details.push(DeadlockInfo { locktag: tag.clone(), lockmode: mode,
+                                                 proc_: check });
+                return true
+            }
+        }
+
+        // Soft edges, to Procs ahead of us in the wait queue wanting a conflicting mode.  If
+        // we have already reordered the queue, use the new order; its soft edges can still be
+        // reversed by further constraints, and `topo_sort` rejects any that contradict.
+        let queue = match self.wait_orders.iter().find( |order| order.lock == tag) {
+            Some(order) => order.procs.clone(),
+            None => lock.wait_procs.clone(),
+        };
+        for &blocker in queue.iter() {
+            if blocker == check {
+                break
+            }
+            if lockbit_on(blocker.get().wait_lock_mode.get()) & conflict_mask != 0 &&
+               self.find_lock_cycle_recurse(blocker, soft_edges) {
+                soft_edges.push(Edge { waiter: check, blocker: blocker, lock: tag.clone() });
+                self.details.push(DeadlockInfo { locktag: tag, lockmode: mode, proc_: check });
+                return true
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeadlockInfo, deadlock_report, deadlock_timeout, set_deadlock_timeout};
+    use lock::{LockTag, acquire, release_all};
+    use lock::LockMode::*;
+    use lock::tests::{in_lock_table, wait_for_waiters};
+    use process::{mod, ProcNumber};
+
+    use std::boxed::BoxAny;
+    use std::task;
+    use std::time::Duration;
+
+    #[test]
+    fn test_deadlock_timeout() {
+        assert_eq!(deadlock_timeout(), Duration::seconds(1));
+        set_deadlock_timeout(Duration::milliseconds(20));
+        assert_eq!(deadlock_timeout(), Duration::milliseconds(20));
+        assert!(task::try(proc() {
+            set_deadlock_timeout(Duration::zero());
+        }).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let cycle = vec![
+            DeadlockInfo { locktag: LockTag::Advisory(1), lockmode: Share, proc_: ProcNumber(3) },
+            DeadlockInfo {
+                locktag: LockTag::Advisory(2),
+                lockmode: Exclusive,
+                proc_: ProcNumber(7),
+            },
+        ];
+        assert_eq!(deadlock_report(cycle.as_slice()).as_slice(),
+                   "deadlock detected\n\
+                    Proc 3 waits for Share on Advisory(1); blocked by Proc 7.\n\
+                    Proc 7 waits for Exclusive on Advisory(2); blocked by Proc 3.");
+    }
+
+    #[test]
+    fn test_hard_deadlock() {
+        // Two threads lock the same two objects in opposite orders.  One of them is chosen
+        // as the victim, and told about the cycle; the other gets its lock once the victim's
+        // are released.
+        let (first, second) = (LockTag::Advisory(100), LockTag::Advisory(101));
+        let (result_tx, result_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let mut go = Vec::new();
+        for &(a, b) in [(first, second), (second, first)].iter() {
+            let (result_tx, ready_tx) = (result_tx.clone(), ready_tx.clone());
+            let (go_tx, go_rx) = channel();
+            go.push(go_tx);
+            spawn(proc() {
+                let result = task::try(proc() {
+                    set_deadlock_timeout(Duration::milliseconds(20));
+                    acquire(a, Exclusive, false);
+                    ready_tx.send(process::my_proc().pgprocno);
+                    go_rx.recv();
+                    acquire(b, Exclusive, false);
+                    release_all();
+                });
+                result_tx.send(result.map_err( |err| *err.downcast::<String>().unwrap()));
+            });
+        }
+        // Both take their first lock before either asks for its second.
+        let procs = [ready_rx.recv(), ready_rx.recv()];
+        for go_tx in go.iter() {
+            go_tx.send(());
+        }
+        let results = [result_rx.recv(), result_rx.recv()];
+        let errors: Vec<&String> = results.iter().filter_map( |result| result.as_ref().err())
+                                                 .collect();
+        assert_eq!(errors.len(), 1);
+        let report = errors[0].as_slice();
+        assert!(report.starts_with("deadlock detected\n"));
+        for pgprocno in procs.iter() {
+            assert!(report.contains(format!("Proc {} waits", pgprocno.to_uint()).as_slice()));
+            assert!(report.contains(format!("blocked by Proc {}.",
+                                            pgprocno.to_uint()).as_slice()));
+        }
+        assert!(report.contains("on Advisory(100)"));
+        assert!(report.contains("on Advisory(101)"));
+        assert!(!in_lock_table(&first));
+        assert!(!in_lock_table(&second));
+    }
+
+    #[test]
+    fn test_soft_deadlock() {
+        // P1 holds `table` in Share mode, and P2 is waiting for it in Exclusive mode.  P3
+        // holds `row`, and waits for `table` in Share mode behind P2.  When P1 waits for
+        // `row`, nobody can go on as things are; but if P3 goes ahead of P2, it can have its
+        // Share lock straight away, and then everyone finishes in turn.
+        let (table, row) = (LockTag::Advisory(200), LockTag::Advisory(201));
+        let (result_tx, result_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let (go_tx, go_rx) = channel();
+
+        let result_tx_ = result_tx.clone();
+        spawn(proc() {
+            result_tx_.send(task::try(proc() {
+                set_deadlock_timeout(Duration::milliseconds(20));
+                acquire(table, Share, false);
+                ready_tx.send(());
+                go_rx.recv();
+                acquire(row, Exclusive, false);
+                release_all();
+            }).is_ok());
+        });
+        ready_rx.recv();
+
+        let result_tx_ = result_tx.clone();
+        spawn(proc() {
+            result_tx_.send(task::try(proc() {
+                acquire(table, Exclusive, false);
+                release_all();
+            }).is_ok());
+        });
+        wait_for_waiters(&table, 1);
+
+        spawn(proc() {
+            result_tx.send(task::try(proc() {
+                acquire(row, Exclusive, false);
+                acquire(table, Share, false);
+                release_all();
+            }).is_ok());
+        });
+        wait_for_waiters(&table, 2);
+
+        go_tx.send(());
+        for _ in range(0u, 3) {
+            assert!(result_rx.recv());
+        }
+        assert!(!in_lock_table(&table));
+        assert!(!in_lock_table(&row));
+    }
+
+    #[test]
+    fn test_two_constraints_on_one_lock() {
+        // P1 holds `table` in Share mode, and P2 is waiting for it in Exclusive mode.  P3 and
+        // P4 each hold `row` in Share mode, and wait for `table` in Share mode behind P2.  When
+        // P1 waits for `row` in Exclusive mode, there is a cycle through each of P3 and P4,
+        // and breaking both takes moving both ahead of P2 in the one queue.
+        let (table, row) = (LockTag::Advisory(300), LockTag::Advisory(301));
+        let (result_tx, result_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let (go_tx, go_rx) = channel();
+
+        let result_tx_ = result_tx.clone();
+        spawn(proc() {
+            result_tx_.send(task::try(proc() {
+                set_deadlock_timeout(Duration::milliseconds(20));
+                acquire(table, Share, false);
+                ready_tx.send(());
+                go_rx.recv();
+                acquire(row, Exclusive, false);
+                release_all();
+            }).is_ok());
+        });
+        ready_rx.recv();
+
+        let result_tx_ = result_tx.clone();
+        spawn(proc() {
+            result_tx_.send(task::try(proc() {
+                acquire(table, Exclusive, false);
+                release_all();
+            }).is_ok());
+        });
+        wait_for_waiters(&table, 1);
+
+        for n in range(2u, 4) {
+            let result_tx = result_tx.clone();
+            spawn(proc() {
+                result_tx.send(task::try(proc() {
+                    acquire(row, Share, false);
+                    acquire(table, Share, false);
+                    release_all();
+                }).is_ok());
+            });
+            wait_for_waiters(&table, n);
+        }
+
+        go_tx.send(());
+        for _ in range(0u, 4) {
+            assert!(result_rx.recv());
+        }
+        assert!(!in_lock_table(&table));
+        assert!(!in_lock_table(&row));
+    }
+}
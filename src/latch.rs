@@ -0,0 +1,380 @@
+// Latches, for waking a thread that is waiting for something to happen.
+//
+// A latch is a flag that any thread can set, and that its owner can wait for.  Setting a latch
+// that is already set does nothing, so unlike with a semaphore, wakers and sleepers don't have
+// to agree on how many wakeups there will be.  The usual way to wait for some work is:
+//
+//     loop {
+//         latch.reset();
+//         if work_to_do() {
+//             do_stuff();
+//         }
+//         latch.wait(WL_LATCH_SET, None);
+//     }
+//
+// Resetting the latch before checking for work means that a set that happens after the check
+// wakes the wait straight away, rather than getting lost.
+//
+// A wait can also finish when a file descriptor becomes readable or writable.  So that both
+// can be waited for at once, a latch sleeps in poll() on a pipe (the self-pipe trick from
+// PostgreSQL's unix_latch.c): setting the latch writes a byte to the pipe if its owner might be
+// sleeping.  The pipe is only created the first time somebody waits on the latch, so that
+// latches that are never waited on, like those of unused Procs, don't use up file descriptors.
+
+use libc::{mod, c_int, c_void};
+use pg_sema;
+
+use std::cmp;
+use std::i32;
+use std::os;
+use std::uint;
+use std::sync::atomic::{AtomicBool, AtomicUint};
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+use time;
+
+/// `wait` returned because the latch was set.
+pub const WL_LATCH_SET: u32 = 1 << 0;
+/// `wait_or_socket` returned because the socket is readable (or at EOF, or in error).
+pub const WL_SOCKET_READABLE: u32 = 1 << 1;
+/// `wait_or_socket` returned because the socket is writable (or in error).
+pub const WL_SOCKET_WRITEABLE: u32 = 1 << 2;
+/// The wait timed out.
+pub const WL_TIMEOUT: u32 = 1 << 3;
+
+/// Stands in for the self-pipe's file descriptors until it is created.
+const NO_PIPE: uint = uint::MAX;
+
+mod ffi {
+    use libc::{c_int, c_short};
+
+    pub const POLLIN: c_short = 0x1;
+    pub const POLLOUT: c_short = 0x4;
+    pub const POLLERR: c_short = 0x8;
+    pub const POLLHUP: c_short = 0x10;
+
+    #[repr(C)]
+    pub struct pollfd {
+        pub fd: c_int,
+        pub events: c_short,
+        pub revents: c_short,
+    }
+
+    #[cfg(target_os = "linux")]
+    pub type nfds_t = ::libc::c_ulong;
+    #[cfg(not(target_os = "linux"))]
+    pub type nfds_t = ::libc::c_uint;
+
+    extern {
+        pub fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: c_int) -> c_int;
+    }
+}
+
+pub struct Latch {
+    /// true if the latch has been set since it was last reset
+    is_set: AtomicBool,
+    /// true while someone may be sleeping in `wait`, so `set` has to wake them
+    maybe_sleeping: AtomicBool,
+    /// read end of the self-pipe, or NO_PIPE; only the waiter uses it
+    pipe_read: AtomicUint,
+    /// write end of the self-pipe, or NO_PIPE; set before `maybe_sleeping` is first set
+    pipe_write: AtomicUint,
+}
+
+impl Latch {
+    pub fn new() -> Latch {
+        Latch {
+            is_set: AtomicBool::new(false),
+            maybe_sleeping: AtomicBool::new(false),
+            pipe_read: AtomicUint::new(NO_PIPE),
+            pipe_write: AtomicUint::new(NO_PIPE),
+        }
+    }
+
+    /// Create the self-pipe, if this is the first wait.  Called by the waiter.
+    fn init_self_pipe(&self) {
+        if self.pipe_read.load(SeqCst) != NO_PIPE {
+            return
+        }
+        let mut fds = [0 as c_int, ..2];
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) < 0 {
+                panic!("pipe() failed: {}", os::last_os_error());
+            }
+            // Neither end may block: a full pipe already means a wakeup is pending, and a
+            // drained one means there is nothing left to read.
+            for &fd in fds.iter() {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                    panic!("fcntl() failed on self-pipe: {}", os::last_os_error());
+                }
+            }
+        }
+        self.pipe_read.store(fds[0] as uint, SeqCst);
+        self.pipe_write.store(fds[1] as uint, SeqCst);
+    }
+
+    /// Set the latch, waking whoever is waiting on it.  Setting a latch that is already set
+    /// does nothing.  Any thread can set a latch.
+    pub fn set(&self) {
+        // Quick exit if already set.
+        if self.is_set.load(SeqCst) {
+            return
+        }
+        self.is_set.store(true, SeqCst);
+
+        // The waiter announces that it may sleep before checking `is_set` one last time, so
+        // either it sees our store or we see its announcement.
+        if self.maybe_sleeping.load(SeqCst) {
+            self.send_wakeup();
+        }
+    }
+
+    /// Clear the latch.  Only the thread that waits on the latch should reset it, before it
+    /// checks whatever condition it is waiting for.
+    pub fn reset(&self) {
+        self.is_set.store(false, SeqCst);
+    }
+
+    /// Has the latch been set since it was last reset?
+    pub fn is_set(&self) -> bool {
+        self.is_set.load(SeqCst)
+    }
+
+    /// Wait until one of the `events` (`WL_LATCH_SET`) happens, or `timeout` expires.  Returns
+    /// the events that happened, which may include `WL_TIMEOUT`.
+    ///
+    /// Only one thread should wait on a latch at a time, normally its owner.  The latch is not
+    /// reset on the way out; that is up to the caller.
+    pub fn wait(&self, events: u32, timeout: Option<Duration>) -> u32 {
+        self.wait_or_socket(events, None, timeout)
+    }
+
+    /// Like `wait`, but `events` may also include `WL_SOCKET_READABLE` and
+    /// `WL_SOCKET_WRITEABLE`, which wait for `sock` to become readable or writable.
+    pub fn wait_or_socket(&self, events: u32, sock: Option<c_int>, timeout: Option<Duration>)
+                          -> u32 {
+        let socket_events = events & (WL_SOCKET_READABLE | WL_SOCKET_WRITEABLE);
+        assert!(socket_events == 0 || sock.is_some(), "cannot wait on a socket without one");
+        assert!(events & (WL_LATCH_SET | socket_events) != 0 || timeout.is_some(),
+                "waiting for nothing, forever");
+
+        let deadline = timeout.map(pg_sema::deadline_after);
+
+        let mut result = 0;
+        self.init_self_pipe();
+        self.maybe_sleeping.store(true, SeqCst);
+        loop {
+            // If the latch is already set, we're done, without even sleeping.  (If it is set
+            // while we sleep, the self-pipe wakes us up and we come back here.)
+            if events & WL_LATCH_SET != 0 && self.is_set.load(SeqCst) {
+                result |= WL_LATCH_SET;
+                break
+            }
+
+            let cur_timeout = match deadline {
+                Some(deadline) => {
+                    let now = time::precise_time_ns();
+                    if now >= deadline {
+                        result |= WL_TIMEOUT;
+                        break
+                    }
+                    // Round up, so that we don't wake up just before the deadline.
+                    cmp::min((deadline - now + 999_999) / 1_000_000, i32::MAX as u64) as c_int
+                },
+                None => -1,
+            };
+
+            let pipe_read = self.pipe_read.load(SeqCst) as c_int;
+            let mut pfds = [ffi::pollfd { fd: pipe_read, events: ffi::POLLIN, revents: 0 },
+                            ffi::pollfd { fd: -1, events: 0, revents: 0 }];
+            let mut nfds = 1;
+            match sock {
+                Some(sock) if socket_events != 0 => {
+                    let mut sock_events = 0;
+                    if events & WL_SOCKET_READABLE != 0 {
+                        sock_events |= ffi::POLLIN;
+                    }
+                    if events & WL_SOCKET_WRITEABLE != 0 {
+                        sock_events |= ffi::POLLOUT;
+                    }
+                    pfds[1] = ffi::pollfd { fd: sock, events: sock_events, revents: 0 };
+                    nfds = 2;
+                },
+                _ => {},
+            }
+
+            // Sleep.
+            let rc = unsafe { ffi::poll(pfds.as_mut_ptr(), nfds, cur_timeout) };
+            if rc < 0 {
+                if os::errno() as c_int == libc::EINTR {
+                    continue
+                }
+                self.maybe_sleeping.store(false, SeqCst);
+                panic!("poll() failed: {}", os::last_os_error());
+            }
+            if rc == 0 {
+                // Timed out; the deadline check above reports it.
+                continue
+            }
+
+            if nfds == 2 {
+                let revents = pfds[1].revents;
+                // An error or hangup counts as whatever the caller was waiting for, so that
+                // they go and find out about it.
+                let failed = revents & (ffi::POLLERR | ffi::POLLHUP) != 0;
+                if events & WL_SOCKET_READABLE != 0 && (revents & ffi::POLLIN != 0 || failed) {
+                    result |= WL_SOCKET_READABLE;
+                }
+                if events & WL_SOCKET_WRITEABLE != 0 && (revents & ffi::POLLOUT != 0 || failed) {
+                    result |= WL_SOCKET_WRITEABLE;
+                }
+            }
+            if pfds[0].revents & ffi::POLLIN != 0 {
+                self.drain();
+            }
+            if result != 0 {
+                // Report the latch too, if it was set while we were sleeping.
+                if events & WL_LATCH_SET != 0 && self.is_set.load(SeqCst) {
+                    result |= WL_LATCH_SET;
+                }
+                break
+            }
+        }
+        self.maybe_sleeping.store(false, SeqCst);
+        result
+    }
+
+    /// Wake the waiter by writing a byte to the self-pipe.
+    fn send_wakeup(&self) {
+        let fd = self.pipe_write.load(SeqCst) as c_int;
+        let dummy = 0u8;
+        loop {
+            let rc = unsafe { libc::write(fd, &dummy as *const u8 as *const c_void, 1) };
+            if rc < 0 {
+                let errno = os::errno() as c_int;
+                // If the pipe is full, the waiter has plenty of wakeups already.
+                if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
+                    return
+                }
+                if errno == libc::EINTR {
+                    continue
+                }
+                panic!("write() to self-pipe failed: {}", os::last_os_error());
+            }
+            return
+        }
+    }
+
+    /// Read everything written to the self-pipe, so that the next wait sleeps again.
+    fn drain(&self) {
+        let fd = self.pipe_read.load(SeqCst) as c_int;
+        let mut buf = [0u8, ..16];
+        loop {
+            let rc = unsafe {
+                libc::read(fd, buf.as_mut_ptr() as *mut c_void, buf.len() as libc::size_t)
+            };
+            if rc < 0 {
+                let errno = os::errno() as c_int;
+                if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
+                    return
+                }
+                if errno == libc::EINTR {
+                    continue
+                }
+                panic!("read() on self-pipe failed: {}", os::last_os_error());
+            }
+            if rc == 0 {
+                panic!("unexpected EOF on self-pipe");
+            }
+            if (rc as uint) < buf.len() {
+                return
+            }
+        }
+    }
+}
+
+impl Drop for Latch {
+    fn drop(&mut self) {
+        if self.pipe_read.load(SeqCst) != NO_PIPE {
+            unsafe {
+                libc::close(self.pipe_read.load(SeqCst) as c_int);
+                libc::close(self.pipe_write.load(SeqCst) as c_int);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Latch, WL_LATCH_SET, WL_SOCKET_READABLE, WL_SOCKET_WRITEABLE, WL_TIMEOUT};
+    use process::{mod, ProcSignal};
+
+    use libc::{mod, c_int, c_void};
+    use std::sync::Arc;
+    use std::task;
+    use std::time::Duration;
+
+    #[test]
+    fn test_set_reset() {
+        let latch = Latch::new();
+        assert!(!latch.is_set());
+        latch.set();
+        latch.set();
+        assert!(latch.is_set());
+        assert_eq!(latch.wait(WL_LATCH_SET, None), WL_LATCH_SET);
+        // Waiting doesn't reset the latch.
+        assert_eq!(latch.wait(WL_LATCH_SET, Some(Duration::zero())), WL_LATCH_SET);
+        latch.reset();
+        assert_eq!(latch.wait(WL_LATCH_SET, Some(Duration::milliseconds(10))), WL_TIMEOUT);
+    }
+
+    #[test]
+    fn test_wakeup() {
+        let latch = Arc::new(Latch::new());
+        let latch_ = latch.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(latch_.wait(WL_LATCH_SET, Some(Duration::seconds(60))));
+        });
+        latch.set();
+        assert_eq!(rx.recv(), WL_LATCH_SET);
+    }
+
+    #[test]
+    fn test_socket() {
+        let mut fds = [0 as c_int, ..2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let latch = Latch::new();
+        assert_eq!(latch.wait_or_socket(WL_SOCKET_READABLE, Some(fds[0]),
+                                        Some(Duration::milliseconds(10))),
+                   WL_TIMEOUT);
+        assert_eq!(latch.wait_or_socket(WL_SOCKET_WRITEABLE, Some(fds[1]), None),
+                   WL_SOCKET_WRITEABLE);
+        let byte = 0u8;
+        assert_eq!(unsafe { libc::write(fds[1], &byte as *const u8 as *const c_void, 1) }, 1);
+        assert_eq!(latch.wait_or_socket(WL_LATCH_SET | WL_SOCKET_READABLE, Some(fds[0]), None),
+                   WL_SOCKET_READABLE);
+        // A latch that is already set returns straight away, without looking at the socket.
+        latch.set();
+        assert_eq!(latch.wait_or_socket(WL_LATCH_SET | WL_SOCKET_READABLE, Some(fds[0]), None),
+                   WL_LATCH_SET);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn test_proc_latch() {
+        // Interrupts set the Proc's latch, so a thread waiting on it can notice them.
+        assert!(task::try(proc() {
+            let proc_ = process::my_proc();
+            proc_.latch.reset();
+            proc_.signal(ProcSignal::Cancel);
+            assert_eq!(proc_.latch.wait(WL_LATCH_SET, None), WL_LATCH_SET);
+            proc_.latch.reset();
+            proc_.check_for_interrupts();
+        }).is_err());
+    }
+}
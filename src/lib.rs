@@ -6,6 +6,8 @@
 #![feature(unsafe_destructor)]
 
 #[cfg(test)] extern crate test;
+extern crate libc;
+extern crate time;
 
 macro_rules! with_offset(($ty:ty,$field:ident,$data:ident,$b:expr) => {
 unsafe {
@@ -30,12 +32,20 @@ with_offset!($ty, $field, data, ::std::mem::min_align_of_val($field))
 
 pub mod multixact;
 pub mod heap;
-mod s_lock;
+mod pg_sema;
+pub mod latch;
+pub mod s_lock;
 pub mod lwlock;
-#[path = "proc.rs"] mod process;
+pub mod atomic_lwlock;
+mod lockdep;
+#[path = "proc.rs"] pub mod process;
+pub mod procarray;
+pub mod condition_variable;
+pub mod lock;
+pub mod deadlock;
 pub mod trans;
 
-#[deriving(Show)]
+#[deriving(Clone,Eq,Hash,PartialEq,Show)]
 pub struct Oid(u32);
 
 #[deriving(Show)]
@@ -46,18 +56,18 @@ pub type Datum = uint;
 #[deriving(Show)]
 pub struct BlockNumber(u32);
 
-#[deriving(Show)]
+#[deriving(Clone,Eq,Hash,PartialEq,Show)]
 #[repr(C)]
 pub struct BlockIdData {
     bi_hi: u16,
     bi_lo: u16,
 }
 
-#[deriving(Show)]
+#[deriving(Clone,Eq,Hash,PartialEq,Show)]
 #[repr(C)]
 pub struct OffsetNumber(u16);
 
-#[deriving(Show)]
+#[deriving(Clone,Eq,Hash,PartialEq,Show)]
 #[repr(C)]
 pub struct ItemPointerData {
     ip_blkid: BlockIdData,
@@ -0,0 +1,1182 @@
+// The heavyweight lock manager, for locking database objects (PostgreSQL's lock.c).
+//
+// Unlike LWLocks, heavyweight locks are named by a `LockTag` rather than being embedded in
+// the object they protect, come in eight modes with a conflict table between them, and are
+// held until the end of the transaction.  Waiters queue in the order they asked, and are
+// granted the lock by whoever releases it.  A waiter that has waited `deadlock_timeout` checks
+// whether it is part of a deadlock (see `deadlock`).
+//
+// The shared lock table is a hash table split into `NUM_LOCK_PARTITIONS` partitions, each
+// protected by its own LWLock, so that threads locking unrelated objects rarely touch the same
+// LWLock.  Each thread also keeps a table of the locks it holds in its `Proc`, which counts
+// repeated acquisitions without touching shared memory at all.
+//
+// Most relation locks are weak (`AccessShare`, `RowShare` and `RowExclusive`), and weak locks
+// don't conflict with each other.  These are recorded in the locking thread's own `Proc` (the
+// fast path), where only its own LWLock is needed, unless somebody holds or wants a strong
+// lock on a relation in the same partition of `FAST_PATH_STRONG_RELATION_LOCKS`.  A thread
+// taking a strong lock first bumps that count, so that no new fast-path locks are granted,
+// and then transfers any existing ones for its relation into the shared table, where it can
+// see them.
+//
+// There is no transaction manager yet: whoever ends a transaction calls `release_all`.
+
+use self::LockMode::*;
+
+use deadlock::{mod, DeadlockInfo, DeadlockState};
+use latch::{WL_LATCH_SET, WL_TIMEOUT};
+use lwlock::{mod, LWLock};
+use pg_sema;
+use process::{mod, Proc, ProcNumber, MAX_BACKENDS};
+use s_lock::SpinLock;
+use trans::TransactionId;
+use {ItemPointerData, Oid};
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::hash;
+use std::mem;
+use std::sync::{Once, ONCE_INIT};
+use std::time::Duration;
+use time;
+
+/// What is being locked.
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+pub enum LockTag {
+    /// a whole relation
+    Relation(Oid),
+    /// one tuple of a relation
+    Tuple(Oid, ItemPointerData),
+    /// a transaction, which holds an exclusive lock on its own ID until it ends, so that
+    /// others can wait for it to finish
+    Transaction(TransactionId),
+    /// an application-defined key
+    Advisory(u64),
+}
+
+/// The table lock modes, weakest first.  See `LOCK_CONFLICTS` for which conflict.
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+#[repr(u8)]
+pub enum LockMode {
+    /// SELECT
+    AccessShare = 1,
+    /// SELECT FOR UPDATE/FOR SHARE
+    RowShare = 2,
+    /// INSERT, UPDATE, DELETE
+    RowExclusive = 3,
+    /// VACUUM (non-FULL), ANALYZE, CREATE INDEX CONCURRENTLY
+    ShareUpdateExclusive = 4,
+    /// CREATE INDEX (WITHOUT CONCURRENTLY)
+    Share = 5,
+    /// like EXCLUSIVE MODE, but allows ROW SHARE
+    ShareRowExclusive = 6,
+    /// blocks ROW SHARE/SELECT...FOR UPDATE
+    Exclusive = 7,
+    /// ALTER TABLE, DROP TABLE, VACUUM FULL, and unqualified LOCK TABLE
+    AccessExclusive = 8,
+}
+
+/// Every lock mode, weakest first.
+pub static LOCK_MODES: [LockMode, ..8] = [
+    AccessShare,
+    RowShare,
+    RowExclusive,
+    ShareUpdateExclusive,
+    Share,
+    ShareRowExclusive,
+    Exclusive,
+    AccessExclusive,
+];
+
+/// Size of arrays indexed by lock mode; mode 0 is unused.
+pub const MAX_LOCKMODES: uint = 9;
+
+/// A set of lock modes, with bit `1 << mode` for each mode in it.
+pub type LockMask = u16;
+
+#[inline]
+pub fn lockbit_on(mode: LockMode) -> LockMask {
+    1 << (mode as uint)
+}
+
+/// For each lock mode, the modes it conflicts with.
+pub static LOCK_CONFLICTS: [LockMask, ..MAX_LOCKMODES] = [
+    0,
+    // AccessShare
+    1 << 8,
+    // RowShare
+    (1 << 7) | (1 << 8),
+    // RowExclusive
+    (1 << 5) | (1 << 6) | (1 << 7) | (1 << 8),
+    // ShareUpdateExclusive
+    (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7) | (1 << 8),
+    // Share
+    (1 << 3) | (1 << 4) | (1 << 6) | (1 << 7) | (1 << 8),
+    // ShareRowExclusive
+    (1 << 3) | (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7) | (1 << 8),
+    // Exclusive
+    (1 << 2) | (1 << 3) | (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7) | (1 << 8),
+    // AccessExclusive
+    (1 << 1) | (1 << 2) | (1 << 3) | (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7) | (1 << 8),
+];
+
+/// Do locks in modes `a` and `b` conflict?
+#[inline]
+pub fn conflicts(a: LockMode, b: LockMode) -> bool {
+    LOCK_CONFLICTS[a as uint] & lockbit_on(b) != 0
+}
+
+/// The result of `acquire`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum LockAcquireResult {
+    /// The lock wasn't available, and we were asked not to wait.
+    NotAvail,
+    /// We got the lock.
+    Ok,
+    /// We got the lock, and already held it in this mode.
+    AlreadyHeld,
+}
+
+/// Number of partitions the shared lock table is divided into.
+pub const NUM_LOCK_PARTITIONS: uint = 16;
+
+/// Number of relations a thread can lock through the fast path.
+pub const FP_LOCK_SLOTS_PER_BACKEND: uint = 16;
+
+/// Number of counters in `FAST_PATH_STRONG_RELATION_LOCKS`.
+const FAST_PATH_STRONG_LOCK_HASH_PARTITIONS: uint = 1024;
+
+/// A lock in the shared lock table.  Procs that hold it or are waiting for it each have a
+/// `ProcLock` in `proc_locks`.
+#[doc(hidden)]
+pub struct Lock {
+    /// bitmask for lock types already granted
+    pub grant_mask: LockMask,
+    /// bitmask for lock types awaited
+    pub wait_mask: LockMask,
+    /// counts of requested locks, by mode
+    pub requested: [u32, ..MAX_LOCKMODES],
+    /// total of requested[]
+    pub n_requested: u32,
+    /// counts of granted locks, by mode
+    pub granted: [u32, ..MAX_LOCKMODES],
+    /// total of granted[]
+    pub n_granted: u32,
+    /// Procs holding or waiting for the lock
+    pub proc_locks: Vec<ProcLock>,
+    /// Procs waiting for the lock, in the order they will be woken
+    pub wait_procs: Vec<ProcNumber>,
+}
+
+/// One Proc's hold on (or request for) a `Lock`.
+#[doc(hidden)]
+#[deriving(Clone)]
+pub struct ProcLock {
+    pub proc_: ProcNumber,
+    /// modes the Proc holds the lock in
+    pub hold_mask: LockMask,
+}
+
+/// One partition of the shared lock table.
+#[doc(hidden)]
+pub type LockTable = HashMap<LockTag, Lock>;
+
+/// A relation lock held through the fast path, as recorded in its thread's `Proc`.  Other
+/// threads only look at it to transfer it to the shared table.
+pub struct FastPathLocks {
+    /// relations locked through the fast path
+    rel_ids: [Option<Oid>, ..FP_LOCK_SLOTS_PER_BACKEND],
+    /// the (weak) modes each relation is locked in
+    lock_bits: [LockMask, ..FP_LOCK_SLOTS_PER_BACKEND],
+}
+
+impl FastPathLocks {
+    pub fn new() -> FastPathLocks {
+        FastPathLocks {
+            rel_ids: [None, ..FP_LOCK_SLOTS_PER_BACKEND],
+            lock_bits: [0, ..FP_LOCK_SLOTS_PER_BACKEND],
+        }
+    }
+}
+
+/// A thread's own record of a lock it holds, kept in its `Proc`.
+pub struct LocalLock {
+    /// how many times the thread has acquired the lock
+    n_locks: uint,
+    /// true if we took this lock through the fast path (it may have been transferred since)
+    fast_path: bool,
+    /// true if we bumped `FAST_PATH_STRONG_RELATION_LOCKS` for this lock
+    holds_strong_lock_count: bool,
+}
+
+/// How many strong locks are held or wanted on relations in each hash partition.  While a
+/// partition's count is nonzero, nobody may take fast-path locks on its relations.
+static FAST_PATH_STRONG_RELATION_LOCKS:
+    SpinLock<(), [u32, ..FAST_PATH_STRONG_LOCK_HASH_PARTITIONS]> =
+    spin_lock_init!((), [0, ..FAST_PATH_STRONG_LOCK_HASH_PARTITIONS]);
+
+/// The partitions of the shared lock table, allocated by `init_lock_manager` and never freed.
+static mut LOCK_PARTITIONS: *const LWLock<LockTable> = 0 as *const LWLock<LockTable>;
+static LOCK_MANAGER_INIT: Once = ONCE_INIT;
+
+static mut LOCK_MANAGER_TRANCHE: u32 = 0;
+static mut FAST_PATH_TRANCHE: u32 = 0;
+static TRANCHES_INIT: Once = ONCE_INIT;
+
+fn init_tranches() {
+    unsafe {
+        LOCK_MANAGER_TRANCHE = lwlock::new_tranche_id();
+        lwlock::register_tranche(LOCK_MANAGER_TRANCHE, "lock_manager");
+        FAST_PATH_TRANCHE = lwlock::new_tranche_id();
+        lwlock::register_tranche(FAST_PATH_TRANCHE, "lock_fast_path");
+    }
+}
+
+/// The tranche of the LWLocks protecting the shared lock table's partitions.
+pub fn lock_manager_tranche() -> u32 {
+    TRANCHES_INIT.doit(init_tranches);
+    unsafe { LOCK_MANAGER_TRANCHE }
+}
+
+/// The tranche of the LWLocks protecting each `Proc`'s fast-path locks.
+pub fn fast_path_tranche() -> u32 {
+    TRANCHES_INIT.doit(init_tranches);
+    unsafe { FAST_PATH_TRANCHE }
+}
+
+fn init_lock_manager() {
+    let tranche = lock_manager_tranche();
+    let mut partitions = Vec::with_capacity(NUM_LOCK_PARTITIONS);
+    for _ in range(0, NUM_LOCK_PARTITIONS) {
+        partitions.push(LWLock::new(HashMap::new()).in_tranche(tranche));
+    }
+    unsafe {
+        LOCK_PARTITIONS = partitions.as_ptr();
+        mem::forget(partitions);
+    }
+}
+
+/// Which partition of the shared lock table `tag` belongs in.
+#[inline]
+pub fn lock_partition(tag: &LockTag) -> uint {
+    (hash::hash(tag) % NUM_LOCK_PARTITIONS as u64) as uint
+}
+
+/// The LWLock protecting a partition of the shared lock table.  When several are needed at
+/// once, they must be taken in partition order.
+#[doc(hidden)]
+pub fn partition_lock(partition: uint) -> &'static LWLock<LockTable> {
+    assert!(partition < NUM_LOCK_PARTITIONS);
+    LOCK_MANAGER_INIT.doit(init_lock_manager);
+    unsafe { &*LOCK_PARTITIONS.offset(partition as int) }
+}
+
+impl Lock {
+    fn new() -> Lock {
+        Lock {
+            grant_mask: 0,
+            wait_mask: 0,
+            requested: [0, ..MAX_LOCKMODES],
+            n_requested: 0,
+            granted: [0, ..MAX_LOCKMODES],
+            n_granted: 0,
+            proc_locks: Vec::new(),
+            wait_procs: Vec::new(),
+        }
+    }
+
+    /// The index of `proc_`'s `ProcLock`, if it has one.
+    #[doc(hidden)]
+    pub fn proc_lock(&self, proc_: ProcNumber) -> Option<uint> {
+        self.proc_locks.iter().position( |proc_lock| proc_lock.proc_ == proc_)
+    }
+
+    /// The index of `proc_`'s `ProcLock`, making one if need be.
+    fn proc_lock_or_insert(&mut self, proc_: ProcNumber) -> uint {
+        match self.proc_lock(proc_) {
+            Some(index) => index,
+            None => {
+                self.proc_locks.push(ProcLock { proc_: proc_, hold_mask: 0 });
+                self.proc_locks.len() - 1
+            },
+        }
+    }
+
+    /// Would the holder at `holder` have to wait for a lock in `mode`, given what everyone
+    /// else holds?  Locks a Proc holds itself never conflict with its requests.
+    #[doc(hidden)]
+    pub fn check_conflicts(&self, mode: LockMode, holder: uint) -> bool {
+        let conflict_mask = LOCK_CONFLICTS[mode as uint];
+        if conflict_mask & self.grant_mask == 0 {
+            return false
+        }
+        let my_locks = self.proc_locks[holder].hold_mask;
+        for &other in LOCK_MODES.iter() {
+            let bit = lockbit_on(other);
+            if conflict_mask & bit == 0 {
+                continue
+            }
+            let mine = if my_locks & bit != 0 { 1 } else { 0 };
+            if self.granted[other as uint] > mine {
+                return true
+            }
+        }
+        false
+    }
+
+    fn request(&mut self, mode: LockMode) {
+        self.requested[mode as uint] += 1;
+        self.n_requested += 1;
+    }
+
+    fn unrequest(&mut self, mode: LockMode) {
+        self.requested[mode as uint] -= 1;
+        self.n_requested -= 1;
+    }
+
+    /// Grant a requested lock to the holder at `holder`.
+    #[doc(hidden)]
+    pub fn grant(&mut self, mode: LockMode, holder: uint) {
+        self.granted[mode as uint] += 1;
+        self.n_granted += 1;
+        self.grant_mask |= lockbit_on(mode);
+        self.proc_locks[holder].hold_mask |= lockbit_on(mode);
+    }
+
+    /// Take back a granted lock.  Returns true if any waiters might now be able to have the
+    /// lock.
+    fn ungrant(&mut self, mode: LockMode, holder: uint) -> bool {
+        self.unrequest(mode);
+        self.granted[mode as uint] -= 1;
+        self.n_granted -= 1;
+        if self.granted[mode as uint] == 0 {
+            self.grant_mask &= !lockbit_on(mode);
+        }
+        self.proc_locks[holder].hold_mask &= !lockbit_on(mode);
+        LOCK_CONFLICTS[mode as uint] & self.wait_mask != 0
+    }
+
+    /// Recompute `wait_mask` from the wait queue.
+    #[doc(hidden)]
+    pub fn update_wait_mask(&mut self) {
+        self.wait_mask = 0;
+        for &waiter in self.wait_procs.iter() {
+            self.wait_mask |= lockbit_on(waiter.get().wait_lock_mode.get());
+        }
+    }
+
+    /// Take a waiter off the wait queue, without granting it the lock.
+    #[doc(hidden)]
+    pub fn remove_waiter(&mut self, proc_: &Proc) {
+        match self.wait_procs.iter().position( |&waiter| waiter == proc_.pgprocno) {
+            Some(index) => { self.wait_procs.remove(index); },
+            None => panic!("Proc {} is not waiting for this lock", proc_.pgprocno),
+        }
+        self.unrequest(proc_.wait_lock_mode.get());
+        self.update_wait_mask();
+        proc_.lock_waiting.set(false);
+        proc_.wait_lock.set(None);
+    }
+
+    /// Grant the lock to as many waiters as can now have it, in queue order, and wake them
+    /// (PostgreSQL's ProcLockWakeup).  A waiter that conflicts with one ahead of it still
+    /// waits, so that nobody is starved.
+    #[doc(hidden)]
+    pub fn wake_waiters(&mut self) {
+        let mut ahead_requests: LockMask = 0;
+        let mut i = 0;
+        while i < self.wait_procs.len() {
+            let proc_ = self.wait_procs[i].get();
+            let mode = proc_.wait_lock_mode.get();
+            let holder = self.proc_lock(proc_.pgprocno).expect("waiter has no ProcLock");
+            if LOCK_CONFLICTS[mode as uint] & ahead_requests == 0 &&
+               !self.check_conflicts(mode, holder) {
+                self.grant(mode, holder);
+                self.wait_procs.remove(i);
+                proc_.lock_waiting.set(false);
+                proc_.wait_lock.set(None);
+                proc_.latch.set();
+            } else {
+                ahead_requests |= lockbit_on(mode);
+                i += 1;
+            }
+        }
+        self.update_wait_mask();
+    }
+}
+
+/// Drop the `ProcLock` at `holder` if it no longer holds anything, and the lock itself if
+/// nobody wants it any more; otherwise wake any waiters that can now have it.
+fn clean_up_lock(table: &mut LockTable, tag: &LockTag, holder: uint, wakeup_needed: bool) {
+    let remove = {
+        let lock = table.get_mut(tag).expect("lock table corrupted");
+        if lock.proc_locks[holder].hold_mask == 0 {
+            lock.proc_locks.swap_remove(holder);
+        }
+        if lock.n_requested == 0 {
+            true
+        } else {
+            if wakeup_needed {
+                lock.wake_waiters();
+            }
+            false
+        }
+    };
+    if remove {
+        table.remove(tag);
+    }
+}
+
+/// Can this lock be taken through the fast path?
+#[inline]
+fn eligible_for_fast_path(tag: &LockTag, mode: LockMode) -> bool {
+    match *tag {
+        LockTag::Relation(_) => (mode as uint) < (ShareUpdateExclusive as uint),
+        _ => false,
+    }
+}
+
+/// Does this lock conflict with locks that might have been taken through the fast path?
+#[inline]
+fn conflicts_with_fast_path(tag: &LockTag, mode: LockMode) -> bool {
+    match *tag {
+        LockTag::Relation(_) => (mode as uint) > (ShareUpdateExclusive as uint),
+        _ => false,
+    }
+}
+
+#[inline]
+fn strong_lock_partition(tag: &LockTag) -> uint {
+    (hash::hash(tag) % FAST_PATH_STRONG_LOCK_HASH_PARTITIONS as u64) as uint
+}
+
+/// Try to take a weak relation lock through the fast path.  Returns false if there is no free
+/// slot, or a strong lock might conflict.
+fn fast_path_grant(proc_: &Proc, tag: &LockTag, rel_id: Oid, mode: LockMode) -> bool {
+    let mut fp = proc_.fp_locks.lock_exclusive();
+    // Strong lockers bump the count before transferring our fast-path locks, and they need
+    // our fast-path LWLock to do that, so once we have seen a zero count, we can go ahead.
+    let partition = strong_lock_partition(tag);
+    let strong = spin_lock_acquire!(guard = FAST_PATH_STRONG_RELATION_LOCKS, {
+        guard.deref().1[partition]
+    });
+    if strong != 0 {
+        return false
+    }
+    let mut unused_slot = None;
+    for i in range(0, FP_LOCK_SLOTS_PER_BACKEND) {
+        match fp.rel_ids[i] {
+            Some(rel) if rel == rel_id => {
+                fp.lock_bits[i] |= lockbit_on(mode);
+                return true
+            },
+            Some(_) => {},
+            None => if unused_slot.is_none() { unused_slot = Some(i) },
+        }
+    }
+    match unused_slot {
+        Some(i) => {
+            fp.rel_ids[i] = Some(rel_id);
+            fp.lock_bits[i] = lockbit_on(mode);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Release a fast-path lock.  Returns false if it isn't there, because a strong locker moved
+/// it to the shared table.
+fn fast_path_unlock(proc_: &Proc, rel_id: Oid, mode: LockMode) -> bool {
+    let mut fp = proc_.fp_locks.lock_exclusive();
+    for i in range(0, FP_LOCK_SLOTS_PER_BACKEND) {
+        if fp.rel_ids[i] == Some(rel_id) {
+            if fp.lock_bits[i] & lockbit_on(mode) == 0 {
+                return false
+            }
+            fp.lock_bits[i] &= !lockbit_on(mode);
+            if fp.lock_bits[i] == 0 {
+                fp.rel_ids[i] = None;
+            }
+            return true
+        }
+    }
+    false
+}
+
+/// Move every Proc's fast-path locks on `rel_id` into the shared table, so that a strong
+/// locker can see them.  The strong lock count must already have been bumped.
+fn fast_path_transfer(tag: &LockTag, rel_id: Oid) {
+    let partition = partition_lock(lock_partition(tag));
+    for pgprocno in range(0, MAX_BACKENDS) {
+        let proc_ = ProcNumber(pgprocno as u32).get();
+        let mut fp = proc_.fp_locks.lock_exclusive();
+        for i in range(0, FP_LOCK_SLOTS_PER_BACKEND) {
+            if fp.rel_ids[i] != Some(rel_id) {
+                continue
+            }
+            let mut table = partition.lock_exclusive();
+            if !table.contains_key(tag) {
+                table.insert(tag.clone(), Lock::new());
+            }
+            let lock = table.get_mut(tag).unwrap();
+            let holder = lock.proc_lock_or_insert(proc_.pgprocno);
+            for &mode in LOCK_MODES.iter() {
+                if fp.lock_bits[i] & lockbit_on(mode) != 0 {
+                    lock.request(mode);
+                    lock.grant(mode, holder);
+                }
+            }
+            fp.rel_ids[i] = None;
+            fp.lock_bits[i] = 0;
+        }
+    }
+}
+
+fn begin_strong_lock(tag: &LockTag) {
+    let partition = strong_lock_partition(tag);
+    spin_lock_acquire!(mut guard = FAST_PATH_STRONG_RELATION_LOCKS, {
+        guard.deref_mut().1[partition] += 1;
+    })
+}
+
+fn end_strong_lock(tag: &LockTag) {
+    let partition = strong_lock_partition(tag);
+    spin_lock_acquire!(mut guard = FAST_PATH_STRONG_RELATION_LOCKS, {
+        let counts = guard.deref_mut().1;
+        debug_assert!(counts[partition] > 0);
+        counts[partition] -= 1;
+    })
+}
+
+/// Acquire a lock in `mode`, waiting for it if need be, unless `dont_wait` is set.  The lock is
+/// held until it is released by `release`, or by `release_all` at the end of the transaction;
+/// acquiring it again in the same mode just counts another hold.
+///
+/// Panics if waiting would deadlock, and if the thread is interrupted while it waits.
+pub fn acquire(tag: LockTag, mode: LockMode, dont_wait: bool) -> LockAcquireResult {
+    let proc_ = process::my_proc();
+    let key = (tag.clone(), mode);
+    match proc_.local_locks.borrow_mut().get_mut(&key) {
+        Some(local) => {
+            local.n_locks += 1;
+            return LockAcquireResult::AlreadyHeld
+        },
+        None => {},
+    }
+    let mut local = LocalLock { n_locks: 1, fast_path: false, holds_strong_lock_count: false };
+
+    match tag {
+        LockTag::Relation(rel_id) => {
+            if eligible_for_fast_path(&tag, mode) && fast_path_grant(proc_, &tag, rel_id, mode) {
+                local.fast_path = true;
+                proc_.local_locks.borrow_mut().insert(key, local);
+                return LockAcquireResult::Ok
+            }
+            if conflicts_with_fast_path(&tag, mode) {
+                begin_strong_lock(&tag);
+                local.holds_strong_lock_count = true;
+                fast_path_transfer(&tag, rel_id);
+            }
+        },
+        _ => {},
+    }
+
+    let partition = partition_lock(lock_partition(&tag));
+    let mut table = partition.lock_exclusive();
+    if !table.contains_key(&tag) {
+        table.insert(tag.clone(), Lock::new());
+    }
+    let (holder, must_wait) = {
+        let lock = table.get_mut(&tag).unwrap();
+        let holder = lock.proc_lock_or_insert(proc_.pgprocno);
+        lock.request(mode);
+        // Wait behind anyone already waiting for a conflicting mode, even if we could have
+        // the lock now, so that they aren't starved.
+        if LOCK_CONFLICTS[mode as uint] & lock.wait_mask == 0 &&
+           !lock.check_conflicts(mode, holder) {
+            lock.grant(mode, holder);
+            (holder, false)
+        } else {
+            (holder, true)
+        }
+    };
+    if !must_wait {
+        drop(table);
+        proc_.local_locks.borrow_mut().insert(key, local);
+        return LockAcquireResult::Ok
+    }
+
+    let queued = if dont_wait {
+        QueueResult::MustWait
+    } else {
+        enqueue(proc_, table.get_mut(&tag).unwrap(), &tag, mode, holder)
+    };
+    match queued {
+        QueueResult::Granted => {
+            drop(table);
+            proc_.local_locks.borrow_mut().insert(key, local);
+            LockAcquireResult::Ok
+        },
+        QueueResult::Queued => {
+            drop(table);
+            let wait = LockWait {
+                proc_: proc_,
+                tag: tag,
+                mode: mode,
+                local: Some(local),
+                deadlocked: Cell::new(false),
+            };
+            wait.sleep();
+            LockAcquireResult::Ok
+        },
+        not_queued => {
+            table.get_mut(&tag).unwrap().unrequest(mode);
+            clean_up_lock(&mut *table, &tag, holder, false);
+            drop(table);
+            if local.holds_strong_lock_count {
+                end_strong_lock(&tag);
+            }
+            match not_queued {
+                QueueResult::Deadlock(cycle) => {
+                    panic!("{}", deadlock::deadlock_report(cycle.as_slice()));
+                },
+                _ => LockAcquireResult::NotAvail,
+            }
+        },
+    }
+}
+
+enum QueueResult {
+    /// we can have the lock after all
+    Granted,
+    /// we would have to wait, but were told not to
+    MustWait,
+    /// waiting would deadlock, in this cycle
+    Deadlock(Vec<DeadlockInfo>),
+    /// we are on the wait queue
+    Queued,
+}
+
+/// Put `proc_` on the lock's wait queue (PostgreSQL's ProcSleep, up to the sleep).
+///
+/// Normally we go to the back of the queue.  But if we already hold the lock in some mode,
+/// and someone in the queue is waiting for a mode that conflicts with it, we go just in front
+/// of them, since they have to wait for us anyway.  If we would also have to wait for them,
+/// that is a deadlock.
+fn enqueue(proc_: &'static Proc, lock: &mut Lock, tag: &LockTag, mode: LockMode, holder: uint)
+           -> QueueResult {
+    let my_held_locks = lock.proc_locks[holder].hold_mask;
+    let mut position = lock.wait_procs.len();
+    if my_held_locks != 0 {
+        let mut ahead_requests: LockMask = 0;
+        for (i, &waiter) in lock.wait_procs.iter().enumerate() {
+            let waiter = waiter.get();
+            let waiter_mode = waiter.wait_lock_mode.get();
+            if LOCK_CONFLICTS[waiter_mode as uint] & my_held_locks != 0 {
+                // The waiter wants something we hold.  Do we also want something it holds?
+                if LOCK_CONFLICTS[mode as uint] & waiter.held_locks.get() != 0 {
+                    return QueueResult::Deadlock(vec![
+                        DeadlockInfo {
+                            locktag: tag.clone(),
+                            lockmode: mode,
+                            proc_: proc_.pgprocno,
+                        },
+                        DeadlockInfo {
+                            locktag: tag.clone(),
+                            lockmode: waiter_mode,
+                            proc_: waiter.pgprocno,
+                        },
+                    ])
+                }
+                // Going in front of it, can we have the lock straight away?
+                if LOCK_CONFLICTS[mode as uint] & ahead_requests == 0 &&
+                   !lock.check_conflicts(mode, holder) {
+                    lock.grant(mode, holder);
+                    return QueueResult::Granted
+                }
+                position = i;
+                break
+            }
+            ahead_requests |= lockbit_on(waiter_mode);
+        }
+    }
+    lock.wait_procs.insert(position, proc_.pgprocno);
+    lock.wait_mask |= lockbit_on(mode);
+    proc_.wait_lock.set(Some(tag.clone()));
+    proc_.wait_lock_mode.set(mode);
+    proc_.held_locks.set(my_held_locks);
+    proc_.lock_waiting.set(true);
+    QueueResult::Queued
+}
+
+/// A thread waiting for a heavyweight lock.  However the wait ends, dropping this cleans up:
+/// if we were granted the lock it is recorded as ours, so that it will be released; if not,
+/// we are taken off the wait queue.
+struct LockWait {
+    proc_: &'static Proc,
+    tag: LockTag,
+    mode: LockMode,
+    local: Option<LocalLock>,
+    /// set if the deadlock check took us off the wait queue
+    deadlocked: Cell<bool>,
+}
+
+impl LockWait {
+    /// Sleep until a releaser grants us the lock.  If that takes longer than the deadlock
+    /// timeout, check for deadlock, once; panics if we are part of one.
+    fn sleep(&self) {
+        let partition = partition_lock(lock_partition(&self.tag));
+        let deadline = pg_sema::deadline_after(deadlock::deadlock_timeout());
+        let mut deadlock_checked = false;
+        loop {
+            let waiting = {
+                let _table = partition.lock_shared();
+                self.proc_.lock_waiting.get()
+            };
+            if !waiting {
+                return
+            }
+            let timeout = if deadlock_checked {
+                None
+            } else {
+                let now = time::precise_time_ns();
+                let remaining = if now < deadline { deadline - now } else { 0 };
+                Some(Duration::nanoseconds(remaining as i64))
+            };
+            let events = self.proc_.latch.wait(WL_LATCH_SET, timeout);
+            self.proc_.latch.reset();
+            self.proc_.check_for_interrupts();
+            if events & WL_TIMEOUT != 0 {
+                deadlock_checked = true;
+                match check_dead_lock(self.proc_) {
+                    Some(report) => {
+                        self.deadlocked.set(true);
+                        panic!("{}", report);
+                    },
+                    None => {},
+                }
+            }
+        }
+    }
+}
+
+/// Check whether our lock wait is part of a deadlock (PostgreSQL's CheckDeadLock).  If it is
+/// part of one that can't be broken, take us off the wait queue, and return the error to
+/// report.
+fn check_dead_lock(proc_: &Proc) -> Option<String> {
+    // Lock the whole lock table, in partition order, so that the wait-for graph holds still
+    // while we look at it.
+    let mut tables: Vec<_> = range(0, NUM_LOCK_PARTITIONS).map( |partition| {
+        partition_lock(partition).lock_exclusive()
+    }).collect();
+
+    // If we were granted the lock while we got here, there is nothing to do.
+    let tag = match proc_.wait_lock.get() {
+        Some(tag) if proc_.lock_waiting.get() => tag,
+        _ => return None,
+    };
+
+    match deadlock::deadlock_check(proc_.pgprocno, tables.as_mut_slice()) {
+        DeadlockState::HardDeadlock(cycle) => {
+            let table = &mut *tables[lock_partition(&tag)];
+            let holder = {
+                let lock = table.get_mut(&tag).expect("lock table corrupted");
+                lock.remove_waiter(proc_);
+                lock.proc_lock(proc_.pgprocno).unwrap()
+            };
+            clean_up_lock(table, &tag, holder, true);
+            Some(deadlock::deadlock_report(cycle.as_slice()))
+        },
+        // If reordering the queues broke a deadlock, we may even have the lock now.
+        DeadlockState::SoftDeadlock | DeadlockState::NoDeadlock => None,
+    }
+}
+
+impl Drop for LockWait {
+    fn drop(&mut self) {
+        let partition = partition_lock(lock_partition(&self.tag));
+        let granted = {
+            let mut table = partition.lock_exclusive();
+            if self.deadlocked.get() {
+                // The deadlock check already took us off the queue.
+                false
+            } else if self.proc_.lock_waiting.get() {
+                let holder = {
+                    let lock = table.get_mut(&self.tag).expect("lock table corrupted");
+                    lock.remove_waiter(self.proc_);
+                    lock.proc_lock(self.proc_.pgprocno).unwrap()
+                };
+                // Removing us may let those queued behind us go ahead.
+                clean_up_lock(&mut *table, &self.tag, holder, true);
+                false
+            } else {
+                true
+            }
+        };
+        let local = self.local.take().unwrap();
+        if granted {
+            self.proc_.local_locks.borrow_mut().insert((self.tag.clone(), self.mode), local);
+        } else if local.holds_strong_lock_count {
+            end_strong_lock(&self.tag);
+        }
+    }
+}
+
+/// Release one hold on a lock.  Returns false (doing nothing) if we don't hold it in `mode`.
+pub fn release(tag: LockTag, mode: LockMode) -> bool {
+    let proc_ = process::my_proc();
+    let key = (tag, mode);
+    let local = {
+        let mut local_locks = proc_.local_locks.borrow_mut();
+        match local_locks.get_mut(&key) {
+            Some(local) => {
+                local.n_locks -= 1;
+                if local.n_locks > 0 {
+                    return true
+                }
+            },
+            None => return false,
+        }
+        local_locks.remove(&key).unwrap()
+    };
+    let (tag, mode) = key;
+    release_local(proc_, &tag, mode, local);
+    true
+}
+
+/// Release every lock this thread holds, however many times it acquired each.  Called at the
+/// end of the transaction, and when a thread exits.
+pub fn release_all() {
+    let proc_ = process::my_proc();
+    let locks = mem::replace(&mut *proc_.local_locks.borrow_mut(), HashMap::new());
+    for ((tag, mode), local) in locks.into_iter() {
+        release_local(proc_, &tag, mode, local);
+    }
+}
+
+/// Give up our last hold on a lock.
+fn release_local(proc_: &Proc, tag: &LockTag, mode: LockMode, local: LocalLock) {
+    let released = match *tag {
+        LockTag::Relation(rel_id) if local.fast_path => fast_path_unlock(proc_, rel_id, mode),
+        _ => false,
+    };
+    if !released {
+        let partition = partition_lock(lock_partition(tag));
+        let mut table = partition.lock_exclusive();
+        let (holder, wakeup_needed) = {
+            let lock = table.get_mut(tag).expect("lock table corrupted");
+            let holder = lock.proc_lock(proc_.pgprocno).expect("lock table corrupted");
+            debug_assert!(lock.proc_locks[holder].hold_mask & lockbit_on(mode) != 0);
+            (holder, lock.ungrant(mode, holder))
+        };
+        clean_up_lock(&mut *table, tag, holder, wakeup_needed);
+    }
+    if local.holds_strong_lock_count {
+        end_strong_lock(tag);
+    }
+}
+
+/// Lock a relation, waiting if need be.
+pub fn lock_relation(rel_id: Oid, mode: LockMode) {
+    acquire(LockTag::Relation(rel_id), mode, false);
+}
+
+pub fn unlock_relation(rel_id: Oid, mode: LockMode) {
+    release(LockTag::Relation(rel_id), mode);
+}
+
+/// Take the lock on our own transaction ID, which others wait on to wait for us to finish.
+pub fn xact_lock_table_insert(xid: TransactionId) {
+    acquire(LockTag::Transaction(xid), Exclusive, false);
+}
+
+/// Wait for the transaction `xid` to end.
+pub fn xact_lock_table_wait(xid: TransactionId) {
+    let tag = LockTag::Transaction(xid);
+    acquire(tag.clone(), Share, false);
+    release(tag, Share);
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{
+        FP_LOCK_SLOTS_PER_BACKEND,
+        LOCK_MODES,
+        LockAcquireResult,
+        LockTag,
+        acquire,
+        conflicts,
+        lock_partition,
+        partition_lock,
+        release,
+        release_all,
+        xact_lock_table_insert,
+        xact_lock_table_wait,
+    };
+    use super::LockMode::*;
+    use process::{mod, ProcNumber, ProcSignal};
+    use trans::TransactionId;
+    use Oid;
+
+    use std::boxed::BoxAny;
+    use std::task;
+    use std::time::Duration;
+    use std::io::timer;
+
+    /// The number of Procs waiting for `tag`.
+    pub fn waiters(tag: &LockTag) -> uint {
+        let table = partition_lock(lock_partition(tag)).lock_shared();
+        table.get(tag).map_or(0, |lock| lock.wait_procs.len())
+    }
+
+    pub fn wait_for_waiters(tag: &LockTag, n: uint) {
+        while waiters(tag) < n {
+            timer::sleep(Duration::milliseconds(1));
+        }
+    }
+
+    /// Is there an entry for `tag` in the shared lock table?
+    pub fn in_lock_table(tag: &LockTag) -> bool {
+        partition_lock(lock_partition(tag)).lock_shared().contains_key(tag)
+    }
+
+    #[test]
+    fn test_conflicts() {
+        for &a in LOCK_MODES.iter() {
+            for &b in LOCK_MODES.iter() {
+                assert_eq!(conflicts(a, b), conflicts(b, a));
+            }
+        }
+        assert!(!conflicts(AccessShare, Exclusive));
+        assert!(conflicts(AccessShare, AccessExclusive));
+        assert!(!conflicts(RowExclusive, RowExclusive));
+        assert!(conflicts(Share, RowExclusive));
+        assert!(!conflicts(Share, Share));
+        assert!(conflicts(ShareUpdateExclusive, ShareUpdateExclusive));
+        assert!(conflicts(ShareRowExclusive, ShareRowExclusive));
+    }
+
+    #[test]
+    fn test_acquire_release() {
+        let tag = LockTag::Advisory(1);
+        assert_eq!(acquire(tag.clone(), Exclusive, false), LockAcquireResult::Ok);
+        assert_eq!(acquire(tag.clone(), Exclusive, false), LockAcquireResult::AlreadyHeld);
+        // We never conflict with ourselves.
+        assert_eq!(acquire(tag.clone(), AccessExclusive, true), LockAcquireResult::Ok);
+        assert!(release(tag.clone(), Exclusive));
+        assert!(release(tag.clone(), Exclusive));
+        assert!(!release(tag.clone(), Exclusive));
+        assert!(release(tag.clone(), AccessExclusive));
+        assert!(!in_lock_table(&tag));
+    }
+
+    #[test]
+    fn test_not_avail() {
+        let tag = LockTag::Advisory(2);
+        let tag_ = tag.clone();
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel();
+        spawn(proc() {
+            acquire(tag_, Share, false);
+            tx.send(());
+            done_rx.recv();
+            release_all();
+            tx.send(());
+        });
+        rx.recv();
+        assert_eq!(acquire(tag.clone(), Share, true), LockAcquireResult::Ok);
+        assert_eq!(acquire(tag.clone(), Exclusive, true), LockAcquireResult::NotAvail);
+        done_tx.send(());
+        rx.recv();
+        assert_eq!(acquire(tag.clone(), Exclusive, true), LockAcquireResult::Ok);
+        release_all();
+        assert!(!in_lock_table(&tag));
+    }
+
+    #[test]
+    fn test_wait() {
+        let tag = LockTag::Advisory(3);
+        assert_eq!(acquire(tag.clone(), Exclusive, false), LockAcquireResult::Ok);
+        let (tx, rx) = channel();
+        for _ in range(0u, 3) {
+            let (tag, tx) = (tag.clone(), tx.clone());
+            spawn(proc() {
+                acquire(tag, Share, false);
+                tx.send(());
+                // Hold the lock until the thread exits.
+            });
+        }
+        wait_for_waiters(&tag, 3);
+        release(tag.clone(), Exclusive);
+        // The shared waiters are all granted the lock together.
+        for _ in range(0u, 3) {
+            rx.recv();
+        }
+        // And give it up as they exit.
+        while in_lock_table(&tag) {
+            timer::sleep(Duration::milliseconds(1));
+        }
+    }
+
+    #[test]
+    fn test_queue_order() {
+        // A waiter for a weak lock doesn't jump ahead of an earlier waiter it conflicts with.
+        let tag = LockTag::Advisory(4);
+        acquire(tag.clone(), Share, false);
+        let (tx, rx) = channel();
+        let (tag_, tx_) = (tag.clone(), tx.clone());
+        spawn(proc() {
+            acquire(tag_.clone(), Exclusive, false);
+            tx_.send("exclusive");
+            release(tag_, Exclusive);
+        });
+        wait_for_waiters(&tag, 1);
+        let tag_ = tag.clone();
+        spawn(proc() {
+            acquire(tag_.clone(), Share, false);
+            tx.send("share");
+            release(tag_, Share);
+        });
+        wait_for_waiters(&tag, 2);
+        release(tag.clone(), Share);
+        assert_eq!(rx.recv(), "exclusive");
+        assert_eq!(rx.recv(), "share");
+    }
+
+    #[test]
+    fn test_fast_path() {
+        let rel = Oid(16384);
+        let tag = LockTag::Relation(rel);
+        assert_eq!(acquire(tag.clone(), AccessShare, false), LockAcquireResult::Ok);
+        assert_eq!(acquire(tag.clone(), RowExclusive, false), LockAcquireResult::Ok);
+        // Weak locks stay out of the shared table.
+        assert!(!in_lock_table(&tag));
+        {
+            let fp = process::my_proc().fp_locks.lock_shared();
+            assert!(fp.rel_ids.iter().any( |&rel_id| rel_id == Some(rel)));
+        }
+
+        // A strong locker sees them, once it has moved them into the shared table.
+        let tag_ = tag.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(acquire(tag_.clone(), AccessExclusive, true));
+            tx.send(acquire(tag_.clone(), Exclusive, true));
+            release_all();
+        });
+        assert_eq!(rx.recv(), LockAcquireResult::NotAvail);
+        // AccessShare doesn't conflict with Exclusive, but RowExclusive does.
+        assert_eq!(rx.recv(), LockAcquireResult::NotAvail);
+        assert!(in_lock_table(&tag));
+
+        // Releasing a transferred lock finds it in the shared table.
+        release_all();
+        assert!(!in_lock_table(&tag));
+        assert_eq!(acquire(tag.clone(), AccessExclusive, true), LockAcquireResult::Ok);
+        release_all();
+    }
+
+    #[test]
+    fn test_fast_path_slots() {
+        // Once the fast-path slots are full, weak locks go to the shared table.
+        let first = 20000u32;
+        for i in range(0, FP_LOCK_SLOTS_PER_BACKEND as u32 + 1) {
+            acquire(LockTag::Relation(Oid(first + i)), AccessShare, false);
+        }
+        let in_table = range(0, FP_LOCK_SLOTS_PER_BACKEND as u32 + 1).filter( |&i| {
+            in_lock_table(&LockTag::Relation(Oid(first + i)))
+        }).count();
+        assert_eq!(in_table, 1);
+        release_all();
+        for i in range(0, FP_LOCK_SLOTS_PER_BACKEND as u32 + 1) {
+            assert!(!in_lock_table(&LockTag::Relation(Oid(first + i))));
+        }
+    }
+
+    #[test]
+    fn test_xact_lock_table_wait() {
+        let xid = TransactionId::from_u32(1000);
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel();
+        spawn(proc() {
+            xact_lock_table_insert(xid.clone());
+            tx.send(());
+            done_rx.recv();
+            // The transaction ends.
+            release_all();
+        });
+        rx.recv();
+        let (waited_tx, waited_rx) = channel();
+        spawn(proc() {
+            xact_lock_table_wait(xid);
+            waited_tx.send(());
+        });
+        wait_for_waiters(&LockTag::Transaction(TransactionId::from_u32(1000)), 1);
+        done_tx.send(());
+        waited_rx.recv();
+    }
+
+    #[test]
+    fn test_simple_deadlock() {
+        // Two holders of a Share lock both want to upgrade it.  The second to ask would wait
+        // for the first, which is already waiting for it.
+        let tag = LockTag::Advisory(5);
+        let (tx, rx) = channel();
+        let (go_tx, go_rx) = channel();
+        let tag_ = tag.clone();
+        spawn(proc() {
+            acquire(tag_.clone(), Share, false);
+            tx.send(process::my_proc().pgprocno);
+            go_rx.recv();
+            acquire(tag_, Exclusive, false);
+            release_all();
+            tx.send(process::my_proc().pgprocno);
+        });
+        let first = rx.recv();
+        let tag_ = tag.clone();
+        let (loser_tx, loser_rx) = channel();
+        let result = task::try(proc() {
+            acquire(tag_.clone(), Share, false);
+            loser_tx.send(process::my_proc().pgprocno);
+            go_tx.send(());
+            wait_for_waiters(&tag_, 1);
+            acquire(tag_, Exclusive, false);
+        });
+        let loser = loser_rx.recv();
+        // The error lists the cycle, like one found by the deadlock checker.
+        let report = *result.unwrap_err().downcast::<String>().unwrap();
+        assert!(report.starts_with("deadlock detected"));
+        assert!(report.contains(format!("Proc {} waits for Exclusive on Advisory(5); blocked by \
+                                         Proc {}.", loser.to_uint(), first.to_uint()).as_slice()));
+        assert!(report.contains(format!("Proc {} waits for Exclusive on Advisory(5); blocked by \
+                                         Proc {}.", first.to_uint(), loser.to_uint()).as_slice()));
+        // The loser's Share lock went with its thread, so the other upgrade goes ahead.
+        rx.recv();
+        assert!(!in_lock_table(&tag));
+    }
+
+    #[test]
+    fn test_interrupt_wait() {
+        // A cancelled waiter leaves the queue.
+        let tag = LockTag::Advisory(6);
+        acquire(tag.clone(), Exclusive, false);
+        let (tx, rx) = channel();
+        let tag_ = tag.clone();
+        spawn(proc() {
+            let result = task::try(proc() {
+                acquire(tag_, Exclusive, false);
+            });
+            tx.send(result.is_err());
+        });
+        wait_for_waiters(&tag, 1);
+        let waiter: ProcNumber = {
+            let table = partition_lock(lock_partition(&tag)).lock_shared();
+            table.get(&tag).unwrap().wait_procs[0]
+        };
+        waiter.get().signal(ProcSignal::Cancel);
+        assert!(rx.recv());
+        assert_eq!(waiters(&tag), 0);
+        release_all();
+        assert!(!in_lock_table(&tag));
+    }
+}
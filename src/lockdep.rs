@@ -0,0 +1,358 @@
+// Lock-order validator for LWLocks and SpinLocks, compiled in only for debug builds.
+//
+// Each thread remembers the locks it holds, and where it acquired them.  Whenever a thread is
+// about to wait for a lock while holding others, we add an edge from each held lock to the
+// new one in a global lock-order graph.  If a new edge closes a cycle, then threads taking
+// these locks in the orders we have seen can deadlock, whether or not they ever actually did;
+// we panic with the call sites of both acquisitions for every edge in the cycle.
+//
+// Locks are identified by a number assigned the first time they are seen, rather than by
+// address, so that a lock allocated where a dead one used to be doesn't inherit its edges.
+
+use std::cell::UnsafeCell;
+
+/// The call site of an acquisition, as passed to `SpinLock::acquire`.
+pub type FileLine = (&'static str, uint);
+
+/// Call site recorded for acquisitions made without one.
+pub static UNKNOWN_SITE: &'static FileLine = &("<unknown>", 0);
+
+#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+pub enum LockKind {
+    LWLock,
+    SpinLock,
+}
+
+#[cfg(not(ndebug))]
+mod imp {
+    use super::{FileLine, LockKind};
+
+    use std::cell::UnsafeCell;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::intrinsics;
+    use std::mem;
+    use std::sync::{StaticMutex, MUTEX_INIT};
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT};
+    use std::sync::atomic::Ordering::SeqCst;
+
+    #[deriving(Clone, PartialEq, Eq, Hash)]
+    struct LockId {
+        kind: LockKind,
+        id: u32,
+    }
+
+    impl fmt::Show for LockId {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} #{}", self.kind, self.id)
+        }
+    }
+
+    static NEXT_LOCK_ID: AtomicUint = INIT_ATOMIC_UINT;
+
+    impl LockId {
+        /// Identify a lock by its id cell, assigning it an id if it doesn't have one yet.
+        fn new(kind: LockKind, cell: &UnsafeCell<u32>) -> LockId {
+            unsafe {
+                let mut id = intrinsics::atomic_load(cell.get() as *const u32);
+                if id == 0 {
+                    let new_id = (NEXT_LOCK_ID.fetch_add(1, SeqCst) + 1) as u32;
+                    id = match intrinsics::atomic_cxchg(cell.get(), 0, new_id) {
+                        0 => new_id,
+                        // Someone else got there first.
+                        other => other,
+                    };
+                }
+                LockId { kind: kind, id: id }
+            }
+        }
+    }
+
+    /// A lock held by this thread.
+    struct Held {
+        lock: LockId,
+        exclusive: bool,
+        site: FileLine,
+    }
+
+    /// Held locks past this many aren't tracked.
+    const MAX_HELD_LOCKS: uint = 256;
+
+    // Thread-locals with destructors can't be used while the thread is exiting, and threads
+    // release their LWLocks as they exit, so keep the held locks in a fixed-size array.
+    #[thread_local] static mut HELD_LOCKS: [Held, ..MAX_HELD_LOCKS] = [Held {
+        lock: LockId { kind: LockKind::SpinLock, id: 0 },
+        exclusive: false,
+        site: ("", 0),
+    }, ..MAX_HELD_LOCKS];
+    #[thread_local] static mut NUM_HELD_LOCKS: uint = 0;
+
+    /// `to` was acquired at `to_site` while holding the lock the edge belongs to, acquired at
+    /// `from_site`.
+    #[deriving(Clone)]
+    struct Edge {
+        to: LockId,
+        from_site: FileLine,
+        to_site: FileLine,
+    }
+
+    // Guards ORDER_GRAPH.  This can't be a SpinLock, since those are validated too.
+    static ORDER_GRAPH_LOCK: StaticMutex = MUTEX_INIT;
+    // Allocated on first use, and never freed.
+    static mut ORDER_GRAPH: *mut HashMap<LockId, Vec<Edge>> =
+        0 as *mut HashMap<LockId, Vec<Edge>>;
+
+    /// Find a path of edges from `from` to `to`, if there is one.  `visited` holds the locks
+    /// already searched.
+    fn find_path(graph: &HashMap<LockId, Vec<Edge>>, from: &LockId, to: &LockId,
+                 visited: &mut Vec<LockId>) -> Option<Vec<Edge>> {
+        if visited.contains(from) {
+            return None
+        }
+        visited.push(from.clone());
+        let edges = match graph.get(from) {
+            Some(edges) => edges,
+            None => return None,
+        };
+        for edge in edges.iter() {
+            if edge.to == *to {
+                return Some(vec![edge.clone()])
+            }
+            match find_path(graph, &edge.to, to, visited) {
+                Some(mut path) => {
+                    path.insert(0, edge.clone());
+                    return Some(path)
+                },
+                None => {},
+            }
+        }
+        None
+    }
+
+    /// Describe the cycle closed by acquiring `lock` at `site` while holding `held`, where
+    /// `path` leads from `lock` back to `held`.
+    fn report_cycle(held: &Held, lock: &LockId, site: &FileLine, path: Vec<Edge>) -> String {
+        let mut report = format!("lock order inversion: {} acquired at {}:{} \
+                                  while holding {} acquired at {}:{}, \
+                                  but earlier:",
+                                 lock, site.0, site.1, held.lock, held.site.0, held.site.1);
+        let mut from = lock.clone();
+        for edge in path.into_iter() {
+            report.push_str(format!(" {} was acquired at {}:{} while holding {} acquired at {}:{};",
+                                    edge.to, edge.to_site.0, edge.to_site.1,
+                                    from, edge.from_site.0, edge.from_site.1).as_slice());
+            from = edge.to;
+        }
+        report
+    }
+
+    pub fn check(kind: LockKind, cell: &UnsafeCell<u32>, exclusive: bool, site: &FileLine) {
+        let lock = LockId::new(kind, cell);
+        let held = unsafe { HELD_LOCKS.slice_to(NUM_HELD_LOCKS) };
+
+        // Waiting for a lock we already hold would never return, unless both holds are
+        // shared.
+        for h in held.iter() {
+            if h.lock == lock && (h.exclusive || exclusive || kind == LockKind::SpinLock) {
+                panic!("{} acquired at {}:{} is already held by this thread (acquired at {}:{})",
+                       lock, site.0, site.1, h.site.0, h.site.1);
+            }
+        }
+
+        let mut cycle = None;
+        {
+            let _guard = ORDER_GRAPH_LOCK.lock();
+            let graph = unsafe {
+                if ORDER_GRAPH.is_null() {
+                    let graph: Box<HashMap<LockId, Vec<Edge>>> = box HashMap::new();
+                    ORDER_GRAPH = mem::transmute(graph);
+                }
+                &mut *ORDER_GRAPH
+            };
+            for h in held.iter() {
+                if h.lock == lock {
+                    // Another shared hold on an LWLock we have shared.
+                    continue
+                }
+                if graph.get(&h.lock).map_or(false, |edges| edges.iter().any( |e| e.to == lock)) {
+                    // Seen this order before.
+                    continue
+                }
+                // Does the new edge close a cycle?
+                match find_path(graph, &lock, &h.lock, &mut Vec::new()) {
+                    Some(path) => {
+                        cycle = Some(report_cycle(h, &lock, site, path));
+                        break
+                    },
+                    None => {},
+                }
+                if !graph.contains_key(&h.lock) {
+                    graph.insert(h.lock.clone(), Vec::new());
+                }
+                graph.get_mut(&h.lock).unwrap().push(Edge {
+                    to: lock.clone(),
+                    from_site: h.site,
+                    to_site: *site,
+                });
+            }
+        }
+
+        // Don't panic while holding ORDER_GRAPH_LOCK.
+        match cycle {
+            Some(report) => panic!("{}", report),
+            None => {},
+        }
+    }
+
+    pub fn acquired(kind: LockKind, cell: &UnsafeCell<u32>, exclusive: bool, site: &FileLine) {
+        let lock = LockId::new(kind, cell);
+        unsafe {
+            if NUM_HELD_LOCKS < MAX_HELD_LOCKS {
+                HELD_LOCKS[NUM_HELD_LOCKS] = Held { lock: lock, exclusive: exclusive, site: *site };
+                NUM_HELD_LOCKS += 1;
+            }
+        }
+    }
+
+    pub fn released(kind: LockKind, cell: &UnsafeCell<u32>) {
+        let lock = LockId::new(kind, cell);
+        unsafe {
+            // Usually it will be the latest-acquired lock, so search backwards.  Locks that
+            // didn't fit in HELD_LOCKS won't be found.
+            match HELD_LOCKS.slice_to(NUM_HELD_LOCKS).iter().rposition( |h| h.lock == lock) {
+                Some(i) => {
+                    for j in range(i + 1, NUM_HELD_LOCKS) {
+                        HELD_LOCKS[j - 1] = HELD_LOCKS[j];
+                    }
+                    NUM_HELD_LOCKS -= 1;
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+/// Check that waiting for a lock while holding this thread's other locks can't deadlock, and
+/// panic if it can.  Called before every acquisition that might block.
+#[cfg(not(ndebug))]
+#[inline]
+pub fn check(kind: LockKind, id: &UnsafeCell<u32>, exclusive: bool, site: &FileLine) {
+    imp::check(kind, id, exclusive, site)
+}
+
+/// Record that this thread now holds a lock.
+#[cfg(not(ndebug))]
+#[inline]
+pub fn acquired(kind: LockKind, id: &UnsafeCell<u32>, exclusive: bool, site: &FileLine) {
+    imp::acquired(kind, id, exclusive, site)
+}
+
+/// Record that this thread released a lock.
+#[cfg(not(ndebug))]
+#[inline]
+pub fn released(kind: LockKind, id: &UnsafeCell<u32>) {
+    imp::released(kind, id)
+}
+
+#[cfg(ndebug)]
+#[inline(always)]
+pub fn check(_kind: LockKind, _id: &UnsafeCell<u32>, _exclusive: bool, _site: &FileLine) {}
+
+#[cfg(ndebug)]
+#[inline(always)]
+pub fn acquired(_kind: LockKind, _id: &UnsafeCell<u32>, _exclusive: bool, _site: &FileLine) {}
+
+#[cfg(ndebug)]
+#[inline(always)]
+pub fn released(_kind: LockKind, _id: &UnsafeCell<u32>) {}
+
+#[cfg(all(test, not(ndebug)))]
+mod tests {
+    use lwlock::LWLock;
+    use lwlock::LWLockMode::*;
+    use s_lock::SpinLock;
+
+    use std::task;
+
+    #[test]
+    fn test_consistent_order() {
+        let a = LWLock::new(());
+        let b = LWLock::new(());
+        for _ in range(0u, 2) {
+            let _a = lwlock_exclusive!(a);
+            let _b = lwlock_shared!(b);
+        }
+        // Taking only the second lock is fine, too.
+        let _b = lwlock_exclusive!(b);
+    }
+
+    #[test]
+    fn test_lwlock_inversion() {
+        let a = LWLock::new(());
+        let b = LWLock::new(());
+        {
+            let _a = lwlock_exclusive!(a);
+            let _b = lwlock_exclusive!(b);
+        }
+        // No need for another thread: taking them in the other order is enough.
+        assert!(task::try(proc() {
+            let _b = lwlock_shared!(b);
+            let _a = lwlock_shared!(a);
+        }).is_err());
+    }
+
+    #[test]
+    fn test_three_lock_cycle() {
+        static A: LWLock<()> = lwlock_init!(());
+        static B: LWLock<()> = lwlock_init!(());
+        static C: SpinLock<(), ()> = spin_lock_init!((), ());
+        {
+            let _a = lwlock_exclusive!(A);
+            let _b = lwlock_exclusive!(B);
+        }
+        {
+            let _b = lwlock_exclusive!(B);
+            spin_lock_acquire!(_c = C, {})
+        }
+        assert!(task::try(proc() {
+            spin_lock_acquire!(_c = C, {
+                let _a = lwlock_exclusive!(A);
+            })
+        }).is_err());
+    }
+
+    #[test]
+    fn test_try_acquire_adds_no_order() {
+        let a = LWLock::new(());
+        let b = LWLock::new(());
+        {
+            let _a = lwlock_exclusive!(a);
+            let _b = b.try_lock_exclusive().unwrap();
+        }
+        let _b = lwlock_exclusive!(b);
+        let _a = lwlock_exclusive!(a);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_reacquire_exclusive() {
+        let a = LWLock::new(());
+        let _guard = lwlock_exclusive!(a);
+        lwlock_acquire!(a, Shared);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_upgrade_shared() {
+        let a = LWLock::new(());
+        let _guard = lwlock_shared!(a);
+        lwlock_acquire!(a, Exclusive);
+    }
+
+    #[test]
+    fn test_reacquire_shared() {
+        let a = LWLock::new(());
+        let _guard1 = lwlock_shared!(a);
+        let _guard2 = lwlock_shared!(a);
+    }
+}
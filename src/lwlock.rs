@@ -2,27 +2,281 @@
 
 use self::LWLockMode::*;
 
+use lockdep::{mod, LockKind};
+use pg_sema;
 use s_lock::{
-    SpinLock
+    SpinLock,
+    SpinLockGuard,
 };
-use process;
+use process::{mod, ProcNumber};
 
-use std::thread_local::Key;
 use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT};
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+use time;
+
+/// Tranche of the locks in the main LWLock array, and the default for new locks.
+pub const LWTRANCHE_MAIN: u32 = 0;
+/// First tranche ID handed out by `new_tranche_id`.
+pub const LWTRANCHE_FIRST_USER_DEFINED: u32 = 1;
+/// Number of tranche IDs the registry can name.
+pub const MAX_LWLOCK_TRANCHES: uint = 128;
+
+static LWLOCK_TRANCHES: SpinLock<(), [Option<&'static str>, ..MAX_LWLOCK_TRANCHES]> =
+    spin_lock_init!((), [None, ..MAX_LWLOCK_TRANCHES]);
+
+static LWLOCK_TRANCHE_COUNTER: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Allocate a new tranche ID, distinct from all built-in and previously allocated ones.
+pub fn new_tranche_id() -> u32 {
+    let id = LWLOCK_TRANCHE_COUNTER.fetch_add(1, SeqCst) + LWTRANCHE_FIRST_USER_DEFINED as uint;
+    if id >= MAX_LWLOCK_TRANCHES {
+        panic!("too many LWLock tranches allocated");
+    }
+    id as u32
+}
+
+/// Register a name for a tranche ID, so that diagnostics can say which lock is meant.
+///
+/// Registering a tranche again replaces its name.  Built-in tranches can't be renamed.
+pub fn register_tranche(tranche: u32, name: &'static str) {
+    assert!(tranche >= LWTRANCHE_FIRST_USER_DEFINED, "can't rename built-in LWLock tranche {}",
+            tranche);
+    assert!((tranche as uint) < MAX_LWLOCK_TRANCHES, "LWLock tranche {} out of range", tranche);
+    spin_lock_acquire!(mut guard = LWLOCK_TRANCHES, {
+        guard.deref_mut().1[tranche as uint] = Some(name);
+    })
+}
+
+/// Look up the name a tranche was registered under.
+pub fn tranche_name(tranche: u32) -> Option<&'static str> {
+    match tranche {
+        LWTRANCHE_MAIN => Some("main"),
+        _ if (tranche as uint) < MAX_LWLOCK_TRANCHES => spin_lock_acquire!(guard = LWLOCK_TRANCHES, {
+            guard.deref().1[tranche as uint]
+        }),
+        _ => None,
+    }
+}
+
+/// Contention counters, kept for each lock and summed over each tranche when the
+/// `lwlock-stats` feature is enabled.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct LWLockStats {
+    /// # of times the lock was acquired in shared mode
+    pub sh_acquire_count: u64,
+    /// # of times the lock was acquired in exclusive mode
+    pub ex_acquire_count: u64,
+    /// # of times a thread had to sleep on the lock
+    pub block_count: u64,
+    /// # of spin delays taken while acquiring the lock's mutex
+    pub spin_delay_count: u64,
+    /// # of unrelated semaphore wakeups absorbed while sleeping on the lock
+    pub extra_waits: u64,
+    /// total time spent sleeping on the lock, in nanoseconds
+    pub block_time_ns: u64,
+}
+
+pub const LWLOCK_STATS_INIT: LWLockStats = LWLockStats {
+    sh_acquire_count: 0,
+    ex_acquire_count: 0,
+    block_count: 0,
+    spin_delay_count: 0,
+    extra_waits: 0,
+    block_time_ns: 0,
+};
+
+#[cfg(feature = "lwlock-stats")]
+impl LWLockStats {
+    fn add(&mut self, other: &LWLockStats) {
+        self.sh_acquire_count += other.sh_acquire_count;
+        self.ex_acquire_count += other.ex_acquire_count;
+        self.block_count += other.block_count;
+        self.spin_delay_count += other.spin_delay_count;
+        self.extra_waits += other.extra_waits;
+        self.block_time_ns += other.block_time_ns;
+    }
+}
+
+/// What each lock keeps its counters in; empty unless the `lwlock-stats` feature is enabled.
+#[cfg(feature = "lwlock-stats")]
+#[doc(hidden)]
+pub type LWLockCounters = LWLockStats;
+#[cfg(not(feature = "lwlock-stats"))]
+#[doc(hidden)]
+pub type LWLockCounters = ();
+
+#[cfg(feature = "lwlock-stats")]
+#[doc(hidden)]
+pub const LWLOCK_COUNTERS_INIT: LWLockCounters = LWLOCK_STATS_INIT;
+#[cfg(not(feature = "lwlock-stats"))]
+#[doc(hidden)]
+pub const LWLOCK_COUNTERS_INIT: LWLockCounters = ();
+
+/// Counters gathered by a single call into a lock.  They are added to the lock's counters
+/// while the call holds the lock's mutex, but to its tranche's only once the call is done,
+/// so that calls into different locks don't meet on the tranche totals under their mutexes.
+#[cfg(feature = "lwlock-stats")]
+mod stats {
+    use super::{LWLockCounters, LWLockMode, LWLockStats, LWLOCK_STATS_INIT, MAX_LWLOCK_TRANCHES};
+    use super::LWLockMode::*;
+    use s_lock::SpinLock;
+    use time;
+
+    pub const ENABLED: bool = true;
+
+    static TRANCHE_STATS: SpinLock<(), [LWLockStats, ..MAX_LWLOCK_TRANCHES]> =
+        spin_lock_init!((), [LWLOCK_STATS_INIT, ..MAX_LWLOCK_TRANCHES]);
+
+    pub struct Delta {
+        /// counts not yet added to the lock's counters
+        lock: LWLockStats,
+        /// counts added to the lock's counters but not yet to its tranche's
+        tranche: LWLockStats,
+        tranche_id: u32,
+    }
+
+    #[inline]
+    pub fn now() -> u64 {
+        time::precise_time_ns()
+    }
+
+    impl Delta {
+        #[inline]
+        pub fn new() -> Delta {
+            Delta { lock: LWLOCK_STATS_INIT, tranche: LWLOCK_STATS_INIT, tranche_id: 0 }
+        }
+
+        #[inline]
+        pub fn spin_delays(&mut self, delays: u32) {
+            self.lock.spin_delay_count += delays as u64;
+        }
+
+        #[inline]
+        pub fn acquired(&mut self, mode: LWLockMode) {
+            match mode {
+                Exclusive => self.lock.ex_acquire_count += 1,
+                Shared => self.lock.sh_acquire_count += 1,
+                WaitUntilFree => {},
+            }
+        }
+
+        /// Count one sleep on the lock, which started at `start` (as returned by `now`).
+        #[inline]
+        pub fn blocked(&mut self, start: u64) {
+            self.lock.block_count += 1;
+            self.lock.block_time_ns += now() - start;
+        }
+
+        #[inline]
+        pub fn extra_waits(&mut self, extra_waits: u32) {
+            self.lock.extra_waits += extra_waits as u64;
+        }
+
+        /// Add these counts to a lock's counters and start counting again from zero.  Caller
+        /// must hold the lock's mutex.  The counts reach the tranche's totals when the
+        /// `Delta` is dropped, which must be after the mutex is released.
+        pub fn apply(&mut self, counters: &mut LWLockCounters, tranche: u32) {
+            counters.add(&self.lock);
+            self.tranche.add(&self.lock);
+            self.tranche_id = tranche;
+            self.lock = LWLOCK_STATS_INIT;
+        }
+    }
+
+    impl Drop for Delta {
+        fn drop(&mut self) {
+            if self.tranche == LWLOCK_STATS_INIT {
+                return;
+            }
+            spin_lock_acquire!(mut guard = TRANCHE_STATS, {
+                guard.deref_mut().1[self.tranche_id as uint].add(&self.tranche);
+            })
+        }
+    }
+
+    pub fn tranche_stats(tranche: u32) -> LWLockStats {
+        spin_lock_acquire!(guard = TRANCHE_STATS, {
+            guard.deref().1[tranche as uint].clone()
+        })
+    }
+
+    pub fn reset_tranche_stats(tranche: u32) {
+        spin_lock_acquire!(mut guard = TRANCHE_STATS, {
+            guard.deref_mut().1[tranche as uint] = LWLOCK_STATS_INIT;
+        })
+    }
+}
+
+#[cfg(not(feature = "lwlock-stats"))]
+mod stats {
+    use super::{LWLockCounters, LWLockMode};
+
+    pub const ENABLED: bool = false;
+
+    pub struct Delta;
+
+    #[inline(always)]
+    pub fn now() -> u64 { 0 }
+
+    impl Delta {
+        #[inline(always)]
+        pub fn new() -> Delta { Delta }
+
+        #[inline(always)]
+        pub fn spin_delays(&mut self, _delays: u32) {}
+
+        #[inline(always)]
+        pub fn acquired(&mut self, _mode: LWLockMode) {}
+
+        #[inline(always)]
+        pub fn blocked(&mut self, _start: u64) {}
+
+        #[inline(always)]
+        pub fn extra_waits(&mut self, _extra_waits: u32) {}
+
+        #[inline(always)]
+        pub fn apply(&mut self, _counters: &mut LWLockCounters, _tranche: u32) {}
+    }
+}
+
+/// Take a snapshot of the counters summed over every lock in `tranche`.
+#[cfg(feature = "lwlock-stats")]
+pub fn tranche_stats(tranche: u32) -> LWLockStats {
+    assert!((tranche as uint) < MAX_LWLOCK_TRANCHES, "LWLock tranche {} out of range", tranche);
+    stats::tranche_stats(tranche)
+}
+
+/// Reset the counters summed over every lock in `tranche`.  The counters of the individual
+/// locks are left alone.
+#[cfg(feature = "lwlock-stats")]
+pub fn reset_tranche_stats(tranche: u32) {
+    assert!((tranche as uint) < MAX_LWLOCK_TRANCHES, "LWLock tranche {} out of range", tranche);
+    stats::reset_tranche_stats(tranche)
+}
 
 pub struct LWLockHeader {
     /// T if ok to release waiters
     release_ok: bool,
     /// has an exclusive holder?
     exclusive: bool,
+    /// hand the lock to waiters in FIFO order, rather than letting them retry?
+    fair: bool,
     /// # of shared holders (0..MaxBackends)
     shared: u32,
-    //tranche: u32, /// tranche ID
+    /// tranche ID
+    tranche: u32,
+    /// # of queued Procs waiting to acquire the lock (i.e. not WaitUntilFree)
+    lock_waiters: u32,
     /// head of list of waiting Procs
-    head: Option<&'static Key<process::Proc>>,
+    head: Option<ProcNumber>,
     /// tail of list of waiting Procss
-    tail: Option<&'static Key<process::Proc>>,
+    tail: Option<ProcNumber>,
     // tail is undefined when head is NULL
+    /// contention counters (see `LWLockStats`)
+    stats: LWLockCounters,
 }
 
 impl fmt::Show for LWLockHeader {
@@ -30,19 +284,227 @@ impl fmt::Show for LWLockHeader {
         write!(f, "LWLockHeader {{ \
                   release_ok: {}, \
                   exclusive: {}, \
+                  fair: {}, \
                   shared: {}, \
+                  tranche: {} ({}), \
+                  lock_waiters: {}, \
                   head: {}, \
                   tail: {} }}",
                   self.release_ok,
                   self.exclusive,
+                  self.fair,
                   self.shared,
-                  self.head.map( |p| p as *const _),
-                  self.tail.map( |p| p as *const _),
+                  tranche_name(self.tranche).unwrap_or("unknown"),
+                  self.tranche,
+                  self.lock_waiters,
+                  self.head,
+                  self.tail,
         )
     }
 }
 
-#[deriving(Show)]
+impl LWLockHeader {
+    /// Take the lock in the requested mode if it is immediately available.  Returns true if
+    /// the lock was acquired.  Caller must hold the mutex.
+    ///
+    /// If the lock is fair, newcomers can't take the lock ahead of anybody already queued for
+    /// it, even if they could hold it at the same time as its current holders.  A caller that
+    /// already holds the lock (`holding`) isn't a newcomer, though: the waiters are waiting for
+    /// it too, so queueing it behind them would deadlock.
+    #[inline]
+    fn attempt_lock(&mut self, mode: LWLockMode, holding: bool) -> bool {
+        if self.fair && self.lock_waiters > 0 && !holding {
+            return false
+        }
+        match mode {
+            Exclusive => {
+                if !self.exclusive && self.shared == 0 {
+                    self.exclusive = true;
+                    true
+                } else {
+                    false
+                }
+            },
+            Shared => {
+                if !self.exclusive {
+                    self.shared += 1;
+                    true
+                } else {
+                    false
+                }
+            },
+            WaitUntilFree => unreachable!(),
+        }
+    }
+
+    /// Add a Proc to the end of the wait queue.  Caller must hold the mutex.
+    #[inline]
+    fn enqueue(&mut self, proc_: &'static process::Proc, mode: LWLockMode) {
+        if mode != WaitUntilFree {
+            self.lock_waiters += 1;
+        }
+        proc_.lw_waiting.set(true);
+        proc_.lw_wait_mode.set(mode);
+        proc_.lw_wait_tranche.set(self.tranche);
+        proc_.lw_wait_link.set(None);
+        match self.head {
+            Some(_) => {
+                // Note: we are assuming that tail was set correctly!
+                match self.tail {
+                    Some(tail) => tail.get().lw_wait_link.set(Some(proc_.pgprocno)),
+                    None => unreachable!(),
+                }
+            },
+            None => self.head = Some(proc_.pgprocno)
+        }
+        self.tail = Some(proc_.pgprocno);
+    }
+
+    /// Take a Proc off the wait queue, wherever it is.  Returns false if it wasn't there,
+    /// because a releaser had already dequeued it.  Caller must hold the mutex.
+    fn remove(&mut self, proc_: &'static process::Proc) -> bool {
+        let mut prev: Option<ProcNumber> = None;
+        let mut cur = self.head;
+        loop {
+            match cur {
+                Some(p) if p == proc_.pgprocno => break,
+                Some(p) => {
+                    prev = cur;
+                    cur = p.get().lw_wait_link.get();
+                },
+                None => return false,
+            }
+        }
+        let next = proc_.lw_wait_link.get();
+        match prev {
+            Some(prev) => prev.get().lw_wait_link.set(next),
+            None => self.head = next,
+        }
+        if next.is_none() {
+            self.tail = prev;
+        }
+        proc_.lw_wait_link.set(None);
+        if proc_.lw_wait_mode.get() != WaitUntilFree {
+            self.lock_waiters -= 1;
+        }
+        true
+    }
+
+    /// Take the Procs from the head of the wait queue up to and including `last` off the
+    /// queue, and return the first of them.  Caller must hold the mutex.
+    fn dequeue_through(&mut self, last: &'static process::Proc) -> Option<ProcNumber> {
+        let head = self.head;
+        self.head = last.lw_wait_link.get();
+        last.lw_wait_link.set(None);
+        let mut proc_ = head.map( |p| p.get());
+        loop {
+            match proc_ {
+                Some(p) => {
+                    if p.lw_wait_mode.get() != WaitUntilFree {
+                        self.lock_waiters -= 1;
+                    }
+                    proc_ = p.lw_wait_next();
+                },
+                None => break,
+            }
+        }
+        head
+    }
+
+    /// If the lock is free, take the waiters that should be woken off the wait queue, and
+    /// return the first of them.  Caller must hold the mutex, and should wake the returned
+    /// Procs with `wake_procs` once it has released it.
+    fn dequeue_waiters(&mut self) -> Option<ProcNumber> {
+        if self.fair {
+            return self.hand_off()
+        }
+
+        // If the lock is still held (e.g. I released a non-last shared hold), there cannot
+        // be anything to do.  Also, do not awaken any waiters if someone has already
+        // awakened waiters that haven't yet acquired the lock.
+        let head = self.head;
+        match head {
+            Some(head) if !self.exclusive && self.shared == 0 && self.release_ok => {
+                let mut proc_ = head.get();
+                let mut release_ok = true;
+
+                // First wake up any backends that want to be woken up without
+                // acquiring the lock.
+                while proc_.lw_wait_mode.get() == WaitUntilFree {
+                    match proc_.lw_wait_next() {
+                        Some(next) => proc_ = next,
+                        None => break,
+                    }
+                }
+
+                // Remove the to-be-awakened Procs from the queue.  If the front
+                // waiter wants exclusive lock, awaken him only. Otherwise awaken
+                // as many waiters as want shared access.
+                if proc_.lw_wait_mode.get() != Exclusive {
+                    loop {
+                        match proc_.lw_wait_next() {
+                            Some(next) if next.lw_wait_mode.get() != Exclusive => {
+                                if proc_.lw_wait_mode.get() != WaitUntilFree {
+                                    release_ok = false;
+                                }
+                                proc_ = next;
+                            },
+                            _ => break,
+                        }
+                    }
+                }
+                // prevent additional wakeups until retryer gets to run.  Backends
+                // that are just waiting for the lock to become free don't retry
+                // automatically.
+                if proc_.lw_wait_mode.get() != WaitUntilFree {
+                    release_ok = false;
+                }
+                self.release_ok = release_ok;
+                // proc_ is now the last Proc to be released
+                self.dequeue_through(proc_)
+            },
+            // lock is still held, can't awaken anything
+            _ => None,
+        }
+    }
+
+    /// `dequeue_waiters` for fair locks.  Rather than waking waiters to retry, which would
+    /// let newcomers get in first, take the lock on their behalf, in queue order, for as many
+    /// of them as can hold it together; they wake up holding it.  Waiters that just want the
+    /// lock to become free are woken if it is free when they reach the front of the queue.
+    fn hand_off(&mut self) -> Option<ProcNumber> {
+        let was_free = !self.exclusive && self.shared == 0;
+        let mut last = None;
+        let mut proc_ = self.head.map( |p| p.get());
+        loop {
+            let p = match proc_ {
+                Some(p) => p,
+                None => break,
+            };
+            match p.lw_wait_mode.get() {
+                WaitUntilFree if was_free => {},
+                Shared if !self.exclusive => {
+                    self.shared += 1;
+                    p.lw_granted.set(true);
+                },
+                Exclusive if !self.exclusive && self.shared == 0 => {
+                    self.exclusive = true;
+                    p.lw_granted.set(true);
+                },
+                // The front waiter has to keep waiting, and so does everyone behind it.
+                _ => break,
+            }
+            last = proc_;
+            proc_ = p.lw_wait_next();
+        }
+        match last {
+            Some(last) => self.dequeue_through(last),
+            None => None,
+        }
+    }
+}
+
+#[deriving(PartialEq,Eq,Show)]
 #[repr(u8)]
 pub enum LWLockMode {
     Exclusive,
@@ -56,22 +518,37 @@ pub struct LWLockInner<T> {
     pub data: T,
 }
 
+/// A lightweight lock protecting data of type `T`.
+///
+/// `V` is an optional variable associated with the lock.  It is protected by the lock's mutex
+/// rather than by the lock itself, so the lock holder can publish new values to threads
+/// waiting on the lock without releasing it (see `wait_for_var` and `update_var`).
 #[repr(C)]
-pub struct LWLock<T> {
-    #[doc(hidden)] pub mutex: SpinLock<(), LWLockInner<T>>, // Protects LWLock and queue of PROCs
+pub struct LWLock<T, V = ()> {
+    #[doc(hidden)] pub mutex: SpinLock<V, LWLockInner<T>>, // Protects LWLock and queue of PROCs
 }
 
 macro_rules! lwlock_init(
     ($data:expr) => (
+        lwlock_init!($data, ())
+    );
+    ($data:expr, $var:expr) => (
+        lwlock_init!($data, $var, ::lwlock::LWTRANCHE_MAIN)
+    );
+    ($data:expr, $var:expr, $tranche:expr) => (
         ::lwlock::LWLock {
-            mutex: spin_lock_init!((),
+            mutex: spin_lock_init!($var,
                 ::lwlock::LWLockInner {
                     header: ::lwlock::LWLockHeader {
                         release_ok: true,
                         exclusive: false,
+                        fair: false,
                         shared: 0,
+                        tranche: $tranche,
+                        lock_waiters: 0,
                         head: None,
                         tail: None,
+                        stats: ::lwlock::LWLOCK_COUNTERS_INIT,
                     },
                     data: $data,
                 }
@@ -80,217 +557,1993 @@ macro_rules! lwlock_init(
     )
 )
 
+// Acquire an LWLock in `mode` with `acquire_at`, passing the call site to the lock-order
+// validator.
+macro_rules! lwlock_acquire(($lock:expr, $mode:expr) => ({
+    static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!());
+    $lock.acquire_at($mode, FILE_LINE)
+}))
+
+// Like `lwlock_acquire!`, for `lock_shared_at`.
+macro_rules! lwlock_shared(($lock:expr) => ({
+    static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!());
+    $lock.lock_shared_at(FILE_LINE)
+}))
+
+// Like `lwlock_acquire!`, for `lock_exclusive_at`.
+macro_rules! lwlock_exclusive(($lock:expr) => ({
+    static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!());
+    $lock.lock_exclusive_at(FILE_LINE)
+}))
+
 impl<T> LWLock<T> where T: Send {
     pub fn new(data: T) -> LWLock<T> {
         lwlock_init!(data)
     }
+}
 
-    pub fn acquire(&self, mode: LWLockMode) -> bool {
-        self.acquire_common(mode)
+impl<T> LWLock<T, u64> where T: Send {
+    /// Create a lock with an associated variable, initially `var`.
+    pub fn with_var(data: T, var: u64) -> LWLock<T, u64> {
+        lwlock_init!(data, var)
     }
 
-    fn acquire_common(&self, mode: LWLockMode) -> bool {
+    /// Acquire the lock exclusively, and set its variable to `val` at the same time.
+    pub fn acquire_with_var(&self, val: u64) -> bool {
+        self.acquire_common(Exclusive, Some(val), None, lockdep::UNKNOWN_SITE).unwrap()
+    }
+
+    /// Wait until the lock is free, or until the lock's variable changes from `oldval`.
+    ///
+    /// Returns `None` if the lock was free (or became free), or the variable's new value if
+    /// the lock holder updated it first.  The lock is never acquired.
+    pub fn wait_for_var(&self, oldval: u64) -> Option<u64> {
         process::MY_PROC.with( |thread| {
-            let mut retry = false;
-            let mut result = true;
             let mut extra_waits = 0u32;
+            let mut delta = stats::Delta::new();
 
-            // Assert(!(proc == NULL && IsUnderPostmaster));
-
-            // Ensure we will have room to remember the lock
-            // if (num_held_lwlocks >= MAX_SIMUL_LWLOCKS) ERROR
-
-            // Lock out cancel/die interrupts until we exit the code section protected
-            // by the LWLock.  This ensures that interrupts will not interfere with
-            // manipulations of data structures in shared memory.
-            // HOLD_INTERRUPTS
+            // Lock out cancel/die interrupts while we sleep on the lock.  There is no
+            // cleanup mechanism to remove us from the wait queue if we got interrupted.
+            thread.hold_interrupts();
 
-            // Loop here to try to acquire lock after each time we are signaled by
-            // LWLockRelease.
-            let mut guard;
+            // Loop here to check the lock's status after each time we are signaled.
+            let mut result;
             loop {
-                let must_wait;
-
                 // Acquire mutex.  Time spent holding mutex should be short!
                 static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
-                guard = self.mutex.acquire_guard(FILE_LINE);
+                let mut guard = self.lock_mutex(FILE_LINE, &mut delta);
                 {
-                    let mut lock = guard.deref_mut().1;
-                    // If retrying, allow LWLockRelease to release waiters again
-                    if retry {
-                        lock.header.release_ok = true;
-                    }
-
-                    // If I can get the lock, do so quickly.
-                    match mode {
-                        Exclusive => {
-                            if !lock.header.exclusive && lock.header.shared == 0 {
-                                lock.header.exclusive = true;
-                                must_wait = false
-                            } else {
-                                must_wait = true
-                            }
-                        },
-                        _ => {
-                            if !lock.header.exclusive {
-                                lock.header.shared += 1;
-                                must_wait = false
-                            } else {
-                                must_wait = true
-                            }
-                        }
-                    }
+                    let (value, lock) = guard.deref_mut();
 
-                    if !must_wait {
+                    // Is the lock now free, and if not, does the value match?
+                    let done = if !lock.header.exclusive {
+                        result = None;
+                        true
+                    } else if *value != oldval {
+                        result = Some(*value);
+                        true
+                    } else {
+                        false
+                    };
+                    if done {
+                        delta.extra_waits(extra_waits);
+                        delta.apply(&mut lock.header.stats, lock.header.tranche);
                         break
                     }
 
-                    // Add myself to wait queue.
-                    thread.lw_waiting.set(true);
-                    thread.lw_wait_mode.set(mode);
-                    thread.lw_wait_link.set(None);
-                    match lock.header.head {
-                        Some(_) => {
-                            // Note: we are assuming that tail was set correctly!
-                            match lock.header.tail {
-                                Some(tail) => tail.with( |p| p.lw_wait_link.set(Some(&process::MY_PROC))),
-                                None => unreachable!(),
-                            }
-                        },
-                        None => lock.header.head = Some(&process::MY_PROC)
-                    }
-                    //*lock.header.tail.as_ref().unwrap() = ;
-                    lock.header.tail = Some(&process::MY_PROC);
+                    // Waiting for the lock to be released is as dangerous as acquiring it,
+                    // except that we may already hold it in shared mode.
+                    lockdep::check(LockKind::LWLock, &self.mutex.lockdep_id, false,
+                                   lockdep::UNKNOWN_SITE);
+
+                    // Add myself to wait queue.  Use WaitUntilFree mode, so that
+                    // release and update_var wake us up without handing us the lock.
+                    lock.header.enqueue(process::my_proc(), WaitUntilFree);
                 }
 
                 // Can release the mutex now
                 drop(guard);
 
                 // Wait until awakened.
-                loop {
-                    // TODO: disable interrupts
-                    thread.sem.acquire();
-                    if !thread.lw_waiting.get() {
-                        break
-                    }
-                    extra_waits += 1;
-                }
+                let start = stats::now();
+                extra_waits += wait_until_dequeued(thread);
+                delta.blocked(start);
 
-                // Now loop back and try to acquire lock again
-                retry = true;
-                result = false;
+                // Now loop back and check the status of the lock again.
             }
 
-            // If there's a variable associated with this lock, initialize it
-            // TODO: add this
-
-            // We are done updating shared state of the lock itself.
-            drop(guard);
-
-            // Add lock to list of locks held by this backend
-            // held_lwlocks[num_held_lwlocks++] = lock;
-
             // Fix the process wait semaphore's count for any absorbed wakeups.
             while extra_waits > 0 {
                 extra_waits -= 1;
                 thread.sem.release();
             }
 
+            // Now okay to allow cancel/die interrupts.
+            thread.resume_interrupts();
+
             result
         })
     }
 
-    pub unsafe fn release(&self) {
-        // Remove lock from list of locks held.  Usually, but not always, it will
-        // be the latest-acquired lock; so search array backwards.
+    /// Update the lock's variable, and wake up anyone sleeping in `wait_for_var`.
+    ///
+    /// The caller must hold the lock in exclusive mode.
+    pub fn update_var(&self, val: u64) {
+        let head;
+        {
+            let mut delta = stats::Delta::new();
+
+            // Acquire mutex.  Time spent holding mutex should be short!
+            static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+            let mut guard = self.lock_mutex(FILE_LINE, &mut delta);
+            let (value, lock) = guard.deref_mut();
+            delta.apply(&mut lock.header.stats, lock.header.tranche);
+
+            // we should hold the lock
+            debug_assert!(lock.header.exclusive)
+
+            // Update the lock's value
+            *value = val;
 
+            // See if there are any WaitUntilFree waiters that need to be woken up.
+            head = match lock.header.head.map( |p| p.get()) {
+                Some(first) if first.lw_wait_mode.get() == WaitUntilFree => {
+                    let mut proc_ = first;
+                    loop {
+                        match proc_.lw_wait_next() {
+                            Some(next) if next.lw_wait_mode.get() == WaitUntilFree => proc_ = next,
+                            _ => break,
+                        }
+                    }
+                    // proc_ is now the last Proc to be released
+                    lock.header.dequeue_through(proc_)
+                },
+                _ => None,
+            };
+        }
+
+        // Awaken any waiters I removed from the queue.
+        wake_procs(head);
     }
 }
 
-/*pub const LWLOCK_PADDED_SIZE = 32; // power of 2
-pub const LWLOCK_PADDING = 12; // LWLOCK_PADDED_SIZE - mem::size_of::<LWLock>
+impl<T, V> LWLock<T, V> where T: Send, V: Send {
+    /// Put a newly created lock in a tranche other than the main one.
+    #[inline]
+    pub fn in_tranche(self, tranche: u32) -> LWLock<T, V> {
+        unsafe {
+            (*self.mutex.after.get()).header.tranche = tranche;
+        }
+        self
+    }
 
-#[repr(C)]
-pub struct LWLockPadded {
-    lock: LWLock,
-    _pad: [u8, .. LWLOCK_PADDING]
-}
-
-impl Deref<LWLock> for LWLockPadded {
-    fn deref(&self) -> &LWLock {
-        &*self.lock
-    }
-}
-
-impl DerefMut<LWLock> for LWLockPadded {
-    fn deref_mut(&mut self) -> &mut LWLock {
-        &mut *self.lock
-    }
-}
-
-pub const LWLOCK_PADDED_INIT: LWLockPadded = LWLockPadded {
-    lock: LWLOCK_INIT,
-    _pad: [u8, .. LWLOCK_PADDING]
-}*/
-
-/*#define ShmemIndexLock				(&MainLWLockArray[1].lock)
-#define OidGenLock					(&MainLWLockArray[2].lock)
-#define XidGenLock					(&MainLWLockArray[3].lock)
-#define ProcArrayLock				(&MainLWLockArray[4].lock)
-#define SInvalReadLock				(&MainLWLockArray[5].lock)
-#define SInvalWriteLock				(&MainLWLockArray[6].lock)
-#define WALBufMappingLock			(&MainLWLockArray[7].lock)
-#define WALWriteLock				(&MainLWLockArray[8].lock)
-#define ControlFileLock				(&MainLWLockArray[9].lock)
-#define CheckpointLock				(&MainLWLockArray[10].lock)
-#define CLogControlLock				(&MainLWLockArray[11].lock)
-#define SubtransControlLock			(&MainLWLockArray[12].lock)
-#define MultiXactGenLock			(&MainLWLockArray[13].lock)
-#define MultiXactOffsetControlLock	(&MainLWLockArray[14].lock)
-#define MultiXactMemberControlLock	(&MainLWLockArray[15].lock)
-#define RelCacheInitLock			(&MainLWLockArray[16].lock)
-#define CheckpointerCommLock		(&MainLWLockArray[17].lock)
-#define TwoPhaseStateLock			(&MainLWLockArray[18].lock)
-#define TablespaceCreateLock		(&MainLWLockArray[19].lock)
-#define BtreeVacuumLock				(&MainLWLockArray[20].lock)
-#define AddinShmemInitLock			(&MainLWLockArray[21].lock)
-#define AutovacuumLock				(&MainLWLockArray[22].lock)
-#define AutovacuumScheduleLock		(&MainLWLockArray[23].lock)
-#define SyncScanLock				(&MainLWLockArray[24].lock)
-#define RelationMappingLock			(&MainLWLockArray[25].lock)
-#define AsyncCtlLock				(&MainLWLockArray[26].lock)
-#define AsyncQueueLock				(&MainLWLockArray[27].lock)
-#define SerializableXactHashLock	(&MainLWLockArray[28].lock)
-#define SerializableFinishedListLock		(&MainLWLockArray[29].lock)
-#define SerializablePredicateLockListLock	(&MainLWLockArray[30].lock)
-#define OldSerXidLock				(&MainLWLockArray[31].lock)
-#define SyncRepLock					(&MainLWLockArray[32].lock)
-#define BackgroundWorkerLock		(&MainLWLockArray[33].lock)
-#define DynamicSharedMemoryControlLock		(&MainLWLockArray[34].lock)
-#define AutoFileLock				(&MainLWLockArray[35].lock)
-#define ReplicationSlotAllocationLock	(&MainLWLockArray[36].lock)
-#define ReplicationSlotControlLock		(&MainLWLockArray[37].lock)
-#define CommitTsControlLock			(&MainLWLockArray[38].lock)
-#define CommitTsLock				(&MainLWLockArray[39].lock)*/
+    /// The tranche this lock belongs to.
+    #[inline]
+    pub fn tranche(&self) -> u32 {
+        // The tranche never changes once the lock is shared, so no need for the mutex.
+        unsafe {
+            (*self.mutex.after.get()).header.tranche
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        LWLock,
-    };
+    /// Make a newly created lock fair.
+    ///
+    /// By default, a thread that finds the lock free takes it even if others are queued for
+    /// it, and waiters are woken to retry rather than being given the lock.  That maximizes
+    /// throughput, but a steady stream of shared lockers can keep an exclusive waiter out
+    /// forever.  A fair lock is granted in queue order instead: newcomers queue behind anybody
+    /// already waiting, and on release the lock is handed directly to the waiters at the head
+    /// of the queue.
+    #[inline]
+    pub fn fair(self) -> LWLock<T, V> {
+        unsafe {
+            (*self.mutex.after.get()).header.fair = true;
+        }
+        self
+    }
 
-    #[test]
-    fn test_sh_mem_lock() {
-        static SH_MEM_INDEX_LOCK: LWLock<()> = lwlock_init!(());
+    /// Whether this lock is fair (see `fair`).
+    #[inline]
+    pub fn is_fair(&self) -> bool {
+        // Like the tranche, this never changes once the lock is shared.
+        unsafe {
+            (*self.mutex.after.get()).header.fair
+        }
+    }
 
-        spin_lock_acquire!(guard = SH_MEM_INDEX_LOCK.mutex, {
-            println!("{}", guard.deref().1.header);
+    pub fn acquire(&self, mode: LWLockMode) -> bool {
+        self.acquire_at(mode, lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `acquire`, but tells the lock-order validator that the lock was acquired at
+    /// `file_line`.  See `lwlock_acquire!`.
+    pub fn acquire_at(&self, mode: LWLockMode, file_line: &(&'static str, uint)) -> bool {
+        self.acquire_common(mode, None, None, file_line).unwrap()
+    }
+
+    /// Like `acquire`, but give up if the lock can't be acquired within `timeout`, or if
+    /// another thread cancels the wait with `Proc::cancel_lwlock_wait`.
+    ///
+    /// Either way, this thread is taken off the lock's wait queue before returning an error,
+    /// and doesn't hold the lock.
+    ///
+    /// Unless the thread is in a critical section (e.g. holds other LWLocks), the wait can
+    /// also be interrupted by `Proc::signal`, in which case the interrupt is processed as by
+    /// `process::check_for_interrupts`, once we are off the wait queue.
+    pub fn acquire_timeout(&self, mode: LWLockMode, timeout: Duration) -> Result<bool, LWLockError> {
+        self.acquire_timeout_at(mode, timeout, lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `acquire_timeout`, but tells the lock-order validator that the lock was acquired
+    /// at `file_line`.
+    pub fn acquire_timeout_at(&self, mode: LWLockMode, timeout: Duration,
+                              file_line: &(&'static str, uint)) -> Result<bool, LWLockError> {
+        self.acquire_common(mode, None, Some(pg_sema::deadline_after(timeout)), file_line)
+    }
+
+    /// Acquire the lock if it is free, but never wait for it.
+    ///
+    /// Returns true if the lock was acquired, in which case it must be released with
+    /// `release`.  If the lock is not immediately available, returns false without
+    /// touching the wait queue.
+    pub fn try_acquire(&self, mode: LWLockMode) -> bool {
+        self.try_acquire_at(mode, lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `try_acquire`, but tells the lock-order validator that the lock was acquired at
+    /// `file_line`.
+    pub fn try_acquire_at(&self, mode: LWLockMode, file_line: &(&'static str, uint)) -> bool {
+        assert!(mode != WaitUntilFree, "LWLockMode::WaitUntilFree is only valid for acquire_or_wait");
+
+        process::MY_PROC.with( |thread| {
+            // Ensure we will have room to remember the lock
+            check_held_lwlocks(thread);
+
+            // Lock out cancel/die interrupts until we exit the code section protected
+            // by the LWLock.  This ensures that interrupts will not interfere with
+            // manipulations of data structures in shared memory.
+            thread.hold_interrupts();
+
+            let holding = holds_lwlock(thread, self as *const _ as *const ());
+            let mut delta = stats::Delta::new();
+
+            // Acquire mutex.  Time spent holding mutex should be short!
+            static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+            let mut guard = self.lock_mutex(FILE_LINE, &mut delta);
+
+            // If I can get the lock, do so quickly.
+            let acquired = {
+                let lock = guard.deref_mut().1;
+                let acquired = lock.header.attempt_lock(mode, holding);
+                if acquired {
+                    delta.acquired(mode);
+                }
+                delta.apply(&mut lock.header.stats, lock.header.tranche);
+                acquired
+            };
+
+            // We are done updating shared state of the lock itself.
+            drop(guard);
+
+            if acquired {
+                // Add lock to list of locks held by this backend
+                self.remember(thread, mode, file_line);
+            } else {
+                // Failed to get lock, so release interrupt holdoff
+                thread.resume_interrupts();
+            }
+
+            acquired
+        })
+    }
+
+    /// Acquire the lock, or wait until it is free without acquiring it.
+    ///
+    /// If the lock is immediately available in `mode`, it is acquired and true is returned; the
+    /// caller must then release it.  Otherwise, sleeps until the lock is released by its current
+    /// holder and returns false, *without* taking the lock.  This is meant for callers that
+    /// only need some other backend to finish the work protected by the lock, e.g. flushing
+    /// WAL up to a point: once woken, they recheck whether their work was done for them.
+    pub fn acquire_or_wait(&self, mode: LWLockMode) -> bool {
+        self.acquire_or_wait_at(mode, lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `acquire_or_wait`, but tells the lock-order validator that the lock was acquired
+    /// (or waited for) at `file_line`.
+    pub fn acquire_or_wait_at(&self, mode: LWLockMode, file_line: &(&'static str, uint)) -> bool {
+        assert!(mode != WaitUntilFree, "acquire_or_wait takes the mode to acquire the lock in");
+
+        process::MY_PROC.with( |thread| {
+            let mut extra_waits = 0u32;
+
+            // Ensure we will have room to remember the lock
+            check_held_lwlocks(thread);
+
+            // Make sure waiting can't deadlock
+            lockdep::check(LockKind::LWLock, &self.mutex.lockdep_id, mode == Exclusive, file_line);
+
+            // Lock out cancel/die interrupts until we exit the code section protected
+            // by the LWLock.  This ensures that interrupts will not interfere with
+            // manipulations of data structures in shared memory.
+            thread.hold_interrupts();
+
+            let holding = holds_lwlock(thread, self as *const _ as *const ());
+            let mut delta = stats::Delta::new();
+
+            // Acquire mutex.  Time spent holding mutex should be short!
+            static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+            let mut guard = self.lock_mutex(FILE_LINE, &mut delta);
+
+            // If I can get the lock, do so quickly.
+            let must_wait = {
+                let lock = guard.deref_mut().1;
+                let must_wait = !lock.header.attempt_lock(mode, holding);
+                if must_wait {
+                    // Add myself to wait queue.
+                    lock.header.enqueue(process::my_proc(), WaitUntilFree);
+                } else {
+                    delta.acquired(mode);
+                    delta.apply(&mut lock.header.stats, lock.header.tranche);
+                }
+                must_wait
+            };
+
+            // Can release the mutex now
+            drop(guard);
+
+            if must_wait {
+                // Wait until awakened.
+                let start = stats::now();
+                extra_waits += wait_until_dequeued(thread);
+                delta.blocked(start);
+                delta.extra_waits(extra_waits);
+
+                // We don't take the mutex again after waiting, so we have to go back for it
+                // to record the wait.
+                if stats::ENABLED {
+                    static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+                    let mut guard = self.lock_mutex(FILE_LINE, &mut delta);
+                    let lock = guard.deref_mut().1;
+                    delta.apply(&mut lock.header.stats, lock.header.tranche);
+                }
+            }
+
+            // Fix the process wait semaphore's count for any absorbed wakeups.
+            while extra_waits > 0 {
+                extra_waits -= 1;
+                thread.sem.release();
+            }
+
+            if must_wait {
+                // Failed to get lock, so release interrupt holdoff
+                thread.resume_interrupts();
+            } else {
+                // Add lock to list of locks held by this backend
+                self.remember(thread, mode, file_line);
+            }
+
+            !must_wait
         })
     }
 
-    /*#[test]
-    fn minimal_tuple_padding() {
-        let size = mem::size_of(LWLock);
-        assert_eq!(LWLOCK_PADDED_SIZE, mem::size_of::<LWLock>() + LWLOCK_PADDING)
-    }*/
+    /// Like `lock_shared`, but returns `None` instead of waiting if the lock is not free.
+    #[inline]
+    pub fn try_lock_shared<'a>(&'a self) -> Option<LWLockSharedGuard<'a, T, V>> {
+        if self.try_acquire(Shared) {
+            Some(LWLockSharedGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Like `lock_exclusive`, but returns `None` instead of waiting if the lock is not free.
+    #[inline]
+    pub fn try_lock_exclusive<'a>(&'a self) -> Option<LWLockExclusiveGuard<'a, T, V>> {
+        if self.try_acquire(Exclusive) {
+            Some(LWLockExclusiveGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Acquire the lock in shared mode, returning a guard that releases it when dropped.
+    #[inline]
+    pub fn lock_shared<'a>(&'a self) -> LWLockSharedGuard<'a, T, V> {
+        self.lock_shared_at(lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `lock_shared`, but tells the lock-order validator that the lock was acquired at
+    /// `file_line`.  See `lwlock_shared!`.
+    #[inline]
+    pub fn lock_shared_at<'a>(&'a self,
+                              file_line: &(&'static str, uint)) -> LWLockSharedGuard<'a, T, V> {
+        self.acquire_common(Shared, None, None, file_line).unwrap();
+        LWLockSharedGuard { lock: self }
+    }
+
+    /// Like `lock_shared`, but give up as `acquire_timeout` does.
+    #[inline]
+    pub fn lock_shared_timeout<'a>(&'a self, timeout: Duration)
+                                   -> Result<LWLockSharedGuard<'a, T, V>, LWLockError> {
+        self.acquire_timeout(Shared, timeout).map( |_| LWLockSharedGuard { lock: self })
+    }
+
+    /// Acquire the lock in exclusive mode, returning a guard that releases it when dropped.
+    #[inline]
+    pub fn lock_exclusive<'a>(&'a self) -> LWLockExclusiveGuard<'a, T, V> {
+        self.lock_exclusive_at(lockdep::UNKNOWN_SITE)
+    }
+
+    /// Like `lock_exclusive`, but tells the lock-order validator that the lock was acquired
+    /// at `file_line`.  See `lwlock_exclusive!`.
+    #[inline]
+    pub fn lock_exclusive_at<'a>(&'a self,
+                                 file_line: &(&'static str, uint)) -> LWLockExclusiveGuard<'a, T, V> {
+        self.acquire_common(Exclusive, None, None, file_line).unwrap();
+        LWLockExclusiveGuard { lock: self }
+    }
+
+    /// Like `lock_exclusive`, but give up as `acquire_timeout` does.
+    #[inline]
+    pub fn lock_exclusive_timeout<'a>(&'a self, timeout: Duration)
+                                      -> Result<LWLockExclusiveGuard<'a, T, V>, LWLockError> {
+        self.acquire_timeout(Exclusive, timeout).map( |_| LWLockExclusiveGuard { lock: self })
+    }
+
+    /// Acquire the lock, waiting for it if necessary.  If `deadline` is given (as a
+    /// `time::precise_time_ns` value), the wait gives up once it passes, and can be cancelled
+    /// with `Proc::cancel_lwlock_wait`; otherwise it can't fail.
+    fn acquire_common(&self, mode: LWLockMode, var: Option<V>, deadline: Option<u64>,
+                      file_line: &(&'static str, uint)) -> Result<bool, LWLockError> {
+        assert!(mode != WaitUntilFree, "LWLockMode::WaitUntilFree is only valid for acquire_or_wait");
+
+        process::MY_PROC.with( |thread| {
+            let mut retry = false;
+            let mut result = true;
+            let mut extra_waits = 0u32;
+            let mut delta = stats::Delta::new();
+
+            // Assert(!(proc == NULL && IsUnderPostmaster));
+
+            // Ensure we will have room to remember the lock
+            check_held_lwlocks(thread);
+
+            // Make sure waiting can't deadlock
+            lockdep::check(LockKind::LWLock, &self.mutex.lockdep_id, mode == Exclusive, file_line);
+
+            // Lock out cancel/die interrupts until we exit the code section protected
+            // by the LWLock.  This ensures that interrupts will not interfere with
+            // manipulations of data structures in shared memory.
+            thread.hold_interrupts();
+
+            // A wait we can give up on can be interrupted too, unless we were already in a
+            // critical section.
+            let interruptible = thread.interrupt_holdoff_count.get() == 1;
+
+            let holding = holds_lwlock(thread, self as *const _ as *const ());
+
+            // Loop here to try to acquire lock after each time we are signaled by
+            // LWLockRelease.
+            let mut guard;
+            loop {
+                let must_wait;
+
+                // Acquire mutex.  Time spent holding mutex should be short!
+                static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+                guard = self.lock_mutex(FILE_LINE, &mut delta);
+                {
+                    let mut lock = guard.deref_mut().1;
+                    // If retrying, allow LWLockRelease to release waiters again
+                    if retry {
+                        lock.header.release_ok = true;
+                    }
+
+                    // If a releaser handed me the lock, I already have it.  Otherwise, if I
+                    // can get the lock, do so quickly.
+                    must_wait = if thread.lw_granted.get() {
+                        thread.lw_granted.set(false);
+                        false
+                    } else {
+                        !lock.header.attempt_lock(mode, holding)
+                    };
+
+                    if !must_wait {
+                        delta.acquired(mode);
+                        delta.extra_waits(extra_waits);
+                        delta.apply(&mut lock.header.stats, lock.header.tranche);
+                        break
+                    }
+
+                    // Add myself to wait queue.
+                    lock.header.enqueue(process::my_proc(), mode);
+                }
+
+                // Can release the mutex now
+                drop(guard);
+
+                // Wait until awakened.
+                let start = stats::now();
+                let gave_up = match deadline {
+                    None => {
+                        extra_waits += wait_until_dequeued(thread);
+                        None
+                    },
+                    Some(deadline) => {
+                        let (waits, gave_up) =
+                            wait_until_dequeued_or_timeout(thread, deadline, interruptible);
+                        extra_waits += waits;
+                        gave_up
+                    },
+                };
+                delta.blocked(start);
+
+                match gave_up {
+                    // If we gave up too late, a fair lock may already have been handed to
+                    // us; then we might as well keep it.
+                    Some(err) => {
+                        // Get off the wait queue, so no releaser picks us.
+                        delta.extra_waits(extra_waits);
+                        extra_waits += self.abandon_wait(thread, &mut delta);
+
+                        // Fix the process wait semaphore's count for any absorbed wakeups.
+                        while extra_waits > 0 {
+                            extra_waits -= 1;
+                            thread.sem.release();
+                        }
+
+                        if thread.lw_granted.get() {
+                            retry = true;
+                            result = false;
+                            continue
+                        }
+
+                        thread.resume_interrupts();
+
+                        // If it was an interrupt that woke us, take it now.
+                        thread.check_for_interrupts();
+
+                        return Err(err)
+                    },
+                    None => {},
+                }
+
+                // Now loop back and try to acquire lock again
+                retry = true;
+                result = false;
+            }
+
+            // If there's a variable associated with this lock, initialize it
+            match var {
+                Some(val) => *guard.deref_mut().0 = val,
+                None => {},
+            }
+
+            // We are done updating shared state of the lock itself.
+            drop(guard);
+
+            // Add lock to list of locks held by this backend
+            self.remember(thread, mode, file_line);
+
+            // Fix the process wait semaphore's count for any absorbed wakeups.
+            while extra_waits > 0 {
+                extra_waits -= 1;
+                thread.sem.release();
+            }
+
+            Ok(result)
+        })
+    }
+
+    /// Take this thread off the wait queue, after it gave up waiting for the lock.
+    ///
+    /// Returns the number of wakeups absorbed, as for `wait_until_dequeued`.  `delta`
+    /// already holds the counts for the wait, other than those.
+    fn abandon_wait(&self, thread: &process::Proc, delta: &mut stats::Delta) -> u32 {
+        static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+        let mut guard = self.lock_mutex(FILE_LINE, delta);
+        let (found, head) = {
+            let lock = guard.deref_mut().1;
+            let found = lock.header.remove(process::my_proc());
+            let mut head = None;
+            if found {
+                delta.apply(&mut lock.header.stats, lock.header.tranche);
+                // On a fair lock, we may have been holding up waiters that could have the
+                // lock now.
+                if lock.header.fair {
+                    head = lock.header.hand_off();
+                }
+            }
+            (found, head)
+        };
+        drop(guard);
+
+        if found {
+            thread.lw_waiting.set(false);
+            wake_procs(head);
+            return 0
+        }
+
+        // Somebody else dequeued us and has or will wake us up.  Wait for the scheduled
+        // wakeup, otherwise lw_waiting would get reset at some inconvenient point later.
+        // Most of the time this will immediately return.
+        let extra_waits = wait_until_dequeued(thread);
+        delta.extra_waits(extra_waits);
+
+        // If they handed us the lock, we hold it now, and our caller will take it.
+        if thread.lw_granted.get() {
+            return extra_waits
+        }
+
+        // Whoever dequeued us blocked further wakeups until we retried, which we never
+        // will.  Unblock them, and wake whoever should have been next in line ourselves.
+        let head = {
+            static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+            let mut guard = self.lock_mutex(FILE_LINE, delta);
+            let lock = guard.deref_mut().1;
+            delta.apply(&mut lock.header.stats, lock.header.tranche);
+            lock.header.release_ok = true;
+            lock.header.dequeue_waiters()
+        };
+        wake_procs(head);
+
+        extra_waits
+    }
+
+    /// Acquire the lock's mutex, counting any spin delays in `delta`.  The mutex is invisible
+    /// to the lock-order validator, which sees the LWLock itself instead.
+    #[inline]
+    fn lock_mutex<'a>(&'a self, file_line: &(&'static str, uint), delta: &mut stats::Delta)
+                      -> SpinLockGuard<'a, V, LWLockInner<T>> {
+        let (guard, delays) = self.mutex.acquire_guard_untracked(file_line);
+        delta.spin_delays(delays);
+        guard
+    }
+
+    /// Take a snapshot of this lock's contention counters.
+    #[cfg(feature = "lwlock-stats")]
+    pub fn stats(&self) -> LWLockStats {
+        spin_lock_acquire!(guard = self.mutex, {
+            guard.deref().1.header.stats.clone()
+        })
+    }
+
+    /// Reset this lock's contention counters.  Its tranche's counters are left alone.
+    #[cfg(feature = "lwlock-stats")]
+    pub fn reset_stats(&self) {
+        spin_lock_acquire!(mut guard = self.mutex, {
+            guard.deref_mut().1.header.stats = LWLOCK_STATS_INIT;
+        })
+    }
+
+    /// Add this lock to the list of locks held by `thread`, acquired in `mode` at `file_line`.
+    #[inline]
+    fn remember(&self, thread: &process::Proc, mode: LWLockMode, file_line: &(&'static str, uint)) {
+        remember_lwlock(thread, self as *const _ as *const (), release_held::<T, V>);
+        lockdep::acquired(LockKind::LWLock, &self.mutex.lockdep_id, mode == Exclusive, file_line);
+    }
+
+    /// Release a previously acquired lock.
+    ///
+    /// Unsafe because the caller must actually hold the lock, in whatever mode it was
+    /// acquired.
+    pub unsafe fn release(&self) {
+        process::MY_PROC.with( |thread| {
+            // Remove lock from list of locks held.
+            forget_lwlock(thread, self as *const _ as *const ());
+
+            self.release_internal();
+
+            // Now okay to allow cancel/die interrupts.
+            thread.resume_interrupts();
+        })
+    }
+
+    /// Release the lock without consulting the list of held locks.
+    unsafe fn release_internal(&self) {
+        lockdep::released(LockKind::LWLock, &self.mutex.lockdep_id);
+
+        let head;
+        {
+            let mut delta = stats::Delta::new();
+
+            // Acquire mutex.  Time spent holding mutex should be short!
+            static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+            let mut guard = self.lock_mutex(FILE_LINE, &mut delta);
+            let lock = guard.deref_mut().1;
+            delta.apply(&mut lock.header.stats, lock.header.tranche);
+
+            // Release my hold on lock
+            if lock.header.exclusive {
+                lock.header.exclusive = false;
+            } else {
+                debug_assert!(lock.header.shared > 0)
+                lock.header.shared -= 1;
+            }
+
+            // See if I need to awaken any waiters.
+            head = lock.header.dequeue_waiters();
+
+            // We are done updating shared state of the lock itself.
+        }
+
+        // Awaken any waiters I removed from the queue.
+        wake_procs(head);
+    }
+}
+
+/// Why `acquire_timeout` gave up waiting for a lock.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum LWLockError {
+    /// The timeout expired first.
+    Timeout,
+    /// Another thread cancelled the wait with `Proc::cancel_lwlock_wait`.
+    Cancelled,
+}
+
+/// Like `wait_until_dequeued`, but give up once `deadline` (a `time::precise_time_ns` value)
+/// passes, or if the wait is cancelled.  If `interruptible`, an interrupt sent to `thread`
+/// cancels the wait too, and is left pending for the caller to process.
+///
+/// Returns the number of unrelated wakeups absorbed, and the reason we gave up, if we did.
+/// In that case the caller is still on the wait queue, and must take itself off.
+#[doc(hidden)]
+pub fn wait_until_dequeued_or_timeout(thread: &process::Proc, deadline: u64,
+                                      interruptible: bool) -> (u32, Option<LWLockError>) {
+    let mut extra_waits = 0;
+    let interrupt = if interruptible { Some(&thread.interrupt_pending) } else { None };
+    loop {
+        let now = time::precise_time_ns();
+        if now >= deadline ||
+           !thread.sem.acquire_interruptible(Duration::nanoseconds((deadline - now) as i64),
+                                             interrupt) {
+            if interruptible && thread.interrupt_pending.load(SeqCst) {
+                return (extra_waits, Some(LWLockError::Cancelled))
+            }
+            return (extra_waits, Some(LWLockError::Timeout))
+        }
+        if !thread.lw_waiting.get() {
+            return (extra_waits, None)
+        }
+        // The wakeup that goes with a cancel request isn't an unrelated one; it's ours.
+        if thread.lw_wait_cancel.swap(false, SeqCst) {
+            return (extra_waits, Some(LWLockError::Cancelled))
+        }
+        extra_waits += 1;
+    }
+}
+
+/// Sleep until a releaser takes `thread` off the wait queue it was added to.
+///
+/// Returns the number of unrelated wakeups absorbed while waiting; the caller must give
+/// these back to the semaphore once it is done waiting.
+#[doc(hidden)]
+pub fn wait_until_dequeued(thread: &process::Proc) -> u32 {
+    let mut extra_waits = 0;
+    loop {
+        // Interrupts are held off, so there's no need to wake up for them.
+        thread.sem.acquire();
+        if !thread.lw_waiting.get() {
+            break
+        }
+        extra_waits += 1;
+    }
+    extra_waits
+}
+
+/// Wake a list of Procs that have been removed from a wait queue.
+#[doc(hidden)]
+pub fn wake_procs(mut head: Option<ProcNumber>) {
+    loop {
+        let proc_ = match head {
+            Some(proc_) => proc_.get(),
+            None => break,
+        };
+        head = proc_.lw_wait_link.get();
+        proc_.lw_wait_link.set(None);
+        proc_.lw_waiting.set(false);
+        proc_.sem.release();
+    }
+}
+
+/// Maximum number of LWLocks a single thread can hold at once.
+pub const MAX_SIMUL_LWLOCKS: uint = 200;
+
+/// An entry in a thread's list of held LWLocks.  The type of the protected data is erased so
+/// that locks over different data can be released together.
+pub struct HeldLWLock {
+    lock: *const (),
+    release: unsafe fn(*const ()),
+}
+
+unsafe fn release_held<T, V>(lock: *const ()) where T: Send, V: Send {
+    (*(lock as *const LWLock<T, V>)).release_internal()
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn check_held_lwlocks(thread: &process::Proc) {
+    if thread.held_lwlocks.borrow().len() >= MAX_SIMUL_LWLOCKS {
+        panic!("too many LWLocks taken");
+    }
+}
+
+/// Add a lock to the list of locks held by `thread`.  `release` must release the lock that
+/// `lock` points to, without consulting the list.
+#[doc(hidden)]
+#[inline]
+pub fn remember_lwlock(thread: &process::Proc, lock: *const (), release: unsafe fn(*const ())) {
+    thread.held_lwlocks.borrow_mut().push(HeldLWLock {
+        lock: lock,
+        release: release,
+    });
+}
+
+/// Whether `lock` is in the list of locks held by `thread`.
+#[doc(hidden)]
+#[inline]
+pub fn holds_lwlock(thread: &process::Proc, lock: *const ()) -> bool {
+    thread.held_lwlocks.borrow().iter().any( |h| h.lock == lock)
+}
+
+/// Remove a lock from the list of locks held by `thread`.
+#[doc(hidden)]
+pub fn forget_lwlock(thread: &process::Proc, lock: *const ()) {
+    // Usually, but not always, it will be the latest-acquired lock; so search array
+    // backwards.
+    let mut held = thread.held_lwlocks.borrow_mut();
+    match held.iter().rposition( |h| h.lock == lock) {
+        Some(i) => { held.remove(i); },
+        None => panic!("lock is not held"),
+    }
+}
+
+/// Release all LWLocks held by the current thread.
+///
+/// This is used to clean up after an error, when the code that would normally release the
+/// locks never ran.
+///
+/// No guard for a lock held by this thread may outlive the call.  Locks held through guards
+/// are released too, and dropping such a guard afterwards panics with "lock is not held",
+/// or, if the thread has taken the same lock again since, releases that newer hold instead.
+pub fn release_all() {
+    process::MY_PROC.with( |thread| release_all_held(thread))
+}
+
+/// Release all LWLocks held by `thread`, most recently acquired first.
+#[doc(hidden)]
+pub fn release_all_held(thread: &process::Proc) {
+    loop {
+        let held = match thread.held_lwlocks.borrow_mut().pop() {
+            Some(held) => held,
+            None => break,
+        };
+        unsafe {
+            (held.release)(held.lock);
+        }
+        thread.resume_interrupts();
+    }
+}
+
+#[must_use]
+pub struct LWLockSharedGuard<'a, T: 'a, V: 'a = ()> {
+    lock: &'a LWLock<T, V>,
+}
+
+impl<'a, T, V> Deref<T> for LWLockSharedGuard<'a, T, V> {
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe {
+            &(*self.lock.mutex.after.get()).data
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T, V> Drop for LWLockSharedGuard<'a, T, V> where T: Send, V: Send {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.release();
+        }
+    }
+}
+
+#[must_use]
+pub struct LWLockExclusiveGuard<'a, T: 'a, V: 'a = ()> {
+    lock: &'a LWLock<T, V>,
+}
+
+impl<'a, T, V> Deref<T> for LWLockExclusiveGuard<'a, T, V> {
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        unsafe {
+            &(*self.lock.mutex.after.get()).data
+        }
+    }
+}
+
+impl<'a, T, V> DerefMut<T> for LWLockExclusiveGuard<'a, T, V> {
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut (*self.lock.mutex.after.get()).data
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T, V> Drop for LWLockExclusiveGuard<'a, T, V> where T: Send, V: Send {
+    #[inline(always)]
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.release();
+        }
+    }
+}
+
+/// Size of an `LWLockPadded`, chosen to put each lock on its own cache line so that
+/// neighbouring locks don't contend with each other.  Must be a power of 2.
+#[cfg(not(feature = "lwlock-stats"))]
+pub const LWLOCK_PADDED_SIZE: uint = 64;
+/// The contention counters don't fit in a cache line along with the lock.
+#[cfg(feature = "lwlock-stats")]
+pub const LWLOCK_PADDED_SIZE: uint = 128;
+
+#[cfg(all(target_word_size = "64", not(feature = "lwlock-stats")))]
+pub const LWLOCK_PADDING: uint = 24; // LWLOCK_PADDED_SIZE - mem::size_of::<LWLock<()>>()
+#[cfg(all(target_word_size = "32", not(feature = "lwlock-stats")))]
+pub const LWLOCK_PADDING: uint = 24; // LWLOCK_PADDED_SIZE - mem::size_of::<LWLock<()>>()
+#[cfg(all(target_word_size = "64", feature = "lwlock-stats"))]
+pub const LWLOCK_PADDING: uint = 40; // LWLOCK_PADDED_SIZE - mem::size_of::<LWLock<()>>()
+#[cfg(all(target_word_size = "32", feature = "lwlock-stats"))]
+pub const LWLOCK_PADDING: uint = 40; // LWLOCK_PADDED_SIZE - mem::size_of::<LWLock<()>>()
+
+// Fails to compile unless LWLOCK_PADDED_SIZE is a power of 2.
+#[allow(dead_code)]
+static LWLOCK_PADDED_SIZE_IS_POWER_OF_2: [(), ..1] =
+    [(), ..1 - (LWLOCK_PADDED_SIZE & (LWLOCK_PADDED_SIZE - 1))];
+
+// Fails to compile unless LWLOCK_PADDING really pads an LWLock out to LWLOCK_PADDED_SIZE, since
+// transmute insists on types of the same size.
+#[allow(dead_code)]
+fn lwlock_padded_size_is_lwlock_padded_size(lock: LWLockPadded) -> [u8, ..LWLOCK_PADDED_SIZE] {
+    unsafe { mem::transmute(lock) }
+}
+
+/// An LWLock padded out to `LWLOCK_PADDED_SIZE`, for use in arrays of locks.
+///
+/// Note that we can't ask for an array of these to start on a cache line boundary, so a
+/// lock may still straddle two lines; it just never shares one with more than one neighbour.
+#[repr(C)]
+pub struct LWLockPadded {
+    pub lock: LWLock<()>,
+    #[doc(hidden)] pub _pad: [u8, .. LWLOCK_PADDING]
+}
+
+impl Deref<LWLock<()>> for LWLockPadded {
+    #[inline]
+    fn deref(&self) -> &LWLock<()> {
+        &self.lock
+    }
+}
+
+macro_rules! lwlock_padded_init(
+    () => (
+        ::lwlock::LWLockPadded {
+            lock: lwlock_init!(()),
+            _pad: [0, .. ::lwlock::LWLOCK_PADDING],
+        }
+    )
+)
+
+/// Number of individually named locks in `MAIN_LWLOCK_ARRAY`.
+pub const NUM_INDIVIDUAL_LWLOCKS: uint = 40;
+
+/// The fixed LWLocks protecting the crate's shared data structures, all in the main tranche.
+///
+/// Use the accessor functions below, such as `proc_array_lock()`, rather than indexing this
+/// directly.
+pub static MAIN_LWLOCK_ARRAY: [LWLockPadded, ..NUM_INDIVIDUAL_LWLOCKS] = [
+    lwlock_padded_init!(), // 0: BufFreelistLock
+    lwlock_padded_init!(), // 1: ShmemIndexLock
+    lwlock_padded_init!(), // 2: OidGenLock
+    lwlock_padded_init!(), // 3: XidGenLock
+    lwlock_padded_init!(), // 4: ProcArrayLock
+    lwlock_padded_init!(), // 5: SInvalReadLock
+    lwlock_padded_init!(), // 6: SInvalWriteLock
+    lwlock_padded_init!(), // 7: WALBufMappingLock
+    lwlock_padded_init!(), // 8: WALWriteLock
+    lwlock_padded_init!(), // 9: ControlFileLock
+    lwlock_padded_init!(), // 10: CheckpointLock
+    lwlock_padded_init!(), // 11: CLogControlLock
+    lwlock_padded_init!(), // 12: SubtransControlLock
+    lwlock_padded_init!(), // 13: MultiXactGenLock
+    lwlock_padded_init!(), // 14: MultiXactOffsetControlLock
+    lwlock_padded_init!(), // 15: MultiXactMemberControlLock
+    lwlock_padded_init!(), // 16: RelCacheInitLock
+    lwlock_padded_init!(), // 17: CheckpointerCommLock
+    lwlock_padded_init!(), // 18: TwoPhaseStateLock
+    lwlock_padded_init!(), // 19: TablespaceCreateLock
+    lwlock_padded_init!(), // 20: BtreeVacuumLock
+    lwlock_padded_init!(), // 21: AddinShmemInitLock
+    lwlock_padded_init!(), // 22: AutovacuumLock
+    lwlock_padded_init!(), // 23: AutovacuumScheduleLock
+    lwlock_padded_init!(), // 24: SyncScanLock
+    lwlock_padded_init!(), // 25: RelationMappingLock
+    lwlock_padded_init!(), // 26: AsyncCtlLock
+    lwlock_padded_init!(), // 27: AsyncQueueLock
+    lwlock_padded_init!(), // 28: SerializableXactHashLock
+    lwlock_padded_init!(), // 29: SerializableFinishedListLock
+    lwlock_padded_init!(), // 30: SerializablePredicateLockListLock
+    lwlock_padded_init!(), // 31: OldSerXidLock
+    lwlock_padded_init!(), // 32: SyncRepLock
+    lwlock_padded_init!(), // 33: BackgroundWorkerLock
+    lwlock_padded_init!(), // 34: DynamicSharedMemoryControlLock
+    lwlock_padded_init!(), // 35: AutoFileLock
+    lwlock_padded_init!(), // 36: ReplicationSlotAllocationLock
+    lwlock_padded_init!(), // 37: ReplicationSlotControlLock
+    lwlock_padded_init!(), // 38: CommitTsControlLock
+    lwlock_padded_init!(), // 39: CommitTsLock
+];
+
+macro_rules! main_lwlocks(($($name:ident = $index:expr),+) => ($(
+    #[inline]
+    pub fn $name() -> &'static LWLock<()> {
+        &MAIN_LWLOCK_ARRAY[$index].lock
+    }
+)+))
+
+main_lwlocks!(
+    buf_freelist_lock = 0,
+    shmem_index_lock = 1,
+    oid_gen_lock = 2,
+    xid_gen_lock = 3,
+    proc_array_lock = 4,
+    sinval_read_lock = 5,
+    sinval_write_lock = 6,
+    wal_buf_mapping_lock = 7,
+    wal_write_lock = 8,
+    control_file_lock = 9,
+    checkpoint_lock = 10,
+    clog_control_lock = 11,
+    subtrans_control_lock = 12,
+    multi_xact_gen_lock = 13,
+    multi_xact_offset_control_lock = 14,
+    multi_xact_member_control_lock = 15,
+    rel_cache_init_lock = 16,
+    checkpointer_comm_lock = 17,
+    two_phase_state_lock = 18,
+    tablespace_create_lock = 19,
+    btree_vacuum_lock = 20,
+    addin_shmem_init_lock = 21,
+    autovacuum_lock = 22,
+    autovacuum_schedule_lock = 23,
+    sync_scan_lock = 24,
+    relation_mapping_lock = 25,
+    async_ctl_lock = 26,
+    async_queue_lock = 27,
+    serializable_xact_hash_lock = 28,
+    serializable_finished_list_lock = 29,
+    serializable_predicate_lock_list_lock = 30,
+    old_serxid_lock = 31,
+    sync_rep_lock = 32,
+    background_worker_lock = 33,
+    dynamic_shared_memory_control_lock = 34,
+    auto_file_lock = 35,
+    replication_slot_allocation_lock = 36,
+    replication_slot_control_lock = 37,
+    commit_ts_control_lock = 38,
+    commit_ts_lock = 39
+)
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        LWLock,
+        LWLockError,
+        LWLockPadded,
+        LWLOCK_PADDED_SIZE,
+        LWLOCK_PADDING,
+        MAIN_LWLOCK_ARRAY,
+        NUM_INDIVIDUAL_LWLOCKS,
+        LWTRANCHE_FIRST_USER_DEFINED,
+        LWTRANCHE_MAIN,
+        MAX_SIMUL_LWLOCKS,
+        new_tranche_id,
+        register_tranche,
+        release_all,
+        tranche_name,
+    };
+    use super::{
+        buf_freelist_lock,
+        commit_ts_lock,
+        oid_gen_lock,
+        proc_array_lock,
+        xid_gen_lock,
+    };
+    use super::LWLockMode::*;
+    #[cfg(feature = "lwlock-stats")]
+    use super::{LWLOCK_STATS_INIT, reset_tranche_stats, tranche_stats};
+    use process;
+
+    use std::io::timer;
+    use std::mem;
+    use std::sync::Arc;
+    use std::cmp;
+    use std::sync::atomic::{AtomicBool, AtomicUint, INIT_ATOMIC_BOOL, INIT_ATOMIC_UINT};
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::task;
+    use std::time::Duration;
+    use time;
+
+    const NUM_THREADS: uint = 8;
+    const NUM_ITERS: uint = 1000;
+
+    unsafe fn data<T>(lock: &LWLock<T>) -> &mut T {
+        &mut (*lock.mutex.after.get()).data
+    }
+
+    #[test]
+    fn test_sh_mem_lock() {
+        static SH_MEM_INDEX_LOCK: LWLock<()> = lwlock_init!(());
+
+        spin_lock_acquire!(guard = SH_MEM_INDEX_LOCK.mutex, {
+            println!("{}", guard.deref().1.header);
+        })
+    }
+
+    #[test]
+    fn test_tranches() {
+        assert_eq!(tranche_name(LWTRANCHE_MAIN), Some("main"));
+        let tranche = new_tranche_id();
+        assert!(tranche >= LWTRANCHE_FIRST_USER_DEFINED);
+        assert!(new_tranche_id() != tranche);
+        assert_eq!(tranche_name(tranche), None);
+        register_tranche(tranche, "test_tranches");
+        assert_eq!(tranche_name(tranche), Some("test_tranches"));
+
+        let lock = LWLock::new(()).in_tranche(tranche);
+        assert_eq!(lock.tranche(), tranche);
+        assert_eq!(LWLock::new(()).tranche(), LWTRANCHE_MAIN);
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = format!("{}", guard.deref().1.header);
+            assert!(header.contains("test_tranches"), "{}", header);
+        })
+    }
+
+    #[test]
+    fn test_wait_tranche() {
+        let tranche = new_tranche_id();
+        register_tranche(tranche, "test_wait_tranche");
+        let lock = Arc::new(LWLock::new(()).in_tranche(tranche));
+        let guard = lock.lock_exclusive();
+        let lock_ = lock.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let _guard = lock_.lock_shared();
+            tx.send(());
+        });
+        // Whoever is stuck on the lock can tell which tranche it belongs to.
+        loop {
+            let waiting = spin_lock_acquire!(guard = lock.mutex, {
+                guard.deref().1.header.head.map( |p| p.get().lw_wait_tranche.get())
+            });
+            match waiting {
+                Some(waiting) => { assert_eq!(waiting, tranche); break },
+                None => task::deschedule(),
+            }
+        }
+        drop(guard);
+        rx.recv();
+    }
+
+    #[test]
+    fn test_acquire_release() {
+        let lock = LWLock::new(());
+        assert!(lock.acquire(Exclusive));
+        unsafe { lock.release(); }
+        assert!(lock.acquire(Shared));
+        assert!(lock.acquire(Shared));
+        unsafe {
+            lock.release();
+            lock.release();
+        }
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+            assert!(header.head.is_none());
+        })
+    }
+
+    #[test]
+    fn test_guards() {
+        let lock = LWLock::new(vec![1u]);
+        {
+            let a = lock.lock_shared();
+            let b = lock.lock_shared();
+            assert_eq!(a[0], b[0]);
+            spin_lock_acquire!(guard = lock.mutex, {
+                assert_eq!(guard.deref().1.header.shared, 2);
+            })
+        }
+        {
+            let mut guard = lock.lock_exclusive();
+            guard.push(2);
+            spin_lock_acquire!(guard = lock.mutex, {
+                assert!(guard.deref().1.header.exclusive);
+            })
+        }
+        assert_eq!(*lock.lock_shared(), vec![1, 2]);
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+        })
+    }
+
+    #[test]
+    fn test_try_acquire() {
+        let lock = LWLock::new(());
+        assert!(lock.try_acquire(Shared));
+        assert!(lock.try_acquire(Shared));
+        assert!(!lock.try_acquire(Exclusive));
+        unsafe {
+            lock.release();
+            lock.release();
+        }
+        assert!(lock.try_acquire(Exclusive));
+        assert!(!lock.try_acquire(Shared));
+        assert!(!lock.try_acquire(Exclusive));
+        unsafe { lock.release(); }
+
+        let guard = lock.try_lock_exclusive();
+        assert!(guard.is_some());
+        assert!(lock.try_lock_shared().is_none());
+        drop(guard);
+        assert!(lock.try_lock_exclusive().is_some());
+    }
+
+    #[test]
+    fn test_try_acquire_does_not_queue() {
+        let lock = Arc::new(LWLock::new(()));
+        let guard = lock.lock_exclusive();
+        let lock_ = lock.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(lock_.try_acquire(Shared));
+        });
+        assert!(!rx.recv());
+        spin_lock_acquire!(guard = lock.mutex, {
+            assert!(guard.deref().1.header.head.is_none());
+        })
+        drop(guard);
+    }
+
+    #[test]
+    fn test_acquire_or_wait_free() {
+        let lock = LWLock::new(());
+        assert!(lock.acquire_or_wait(Exclusive));
+        assert!(!lock.try_acquire(Shared));
+        unsafe { lock.release(); }
+        assert!(lock.try_acquire(Exclusive));
+        unsafe { lock.release(); }
+    }
+
+    #[test]
+    fn test_acquire_or_wait_busy() {
+        let lock = Arc::new(LWLock::new(()));
+        lock.acquire(Exclusive);
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_THREADS) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                tx.send(lock.acquire_or_wait(Exclusive));
+            });
+        }
+        // Wait until everyone is queued.
+        while queue_len(&*lock) != NUM_THREADS {
+            task::deschedule();
+        }
+        unsafe { lock.release(); }
+        // All the waiters are woken at once, and none of them take the lock.
+        for _ in range(0, NUM_THREADS) {
+            assert!(!rx.recv());
+        }
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+            assert!(header.head.is_none());
+            assert!(header.release_ok);
+        })
+    }
+
+    #[test]
+    fn test_acquire_or_wait_group_flush() {
+        static LOCK: LWLock<()> = lwlock_init!(());
+        static INSERTED: AtomicUint = INIT_ATOMIC_UINT;
+        static FLUSHED: AtomicUint = INIT_ATOMIC_UINT;
+        static FLUSHES: AtomicUint = INIT_ATOMIC_UINT;
+
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_THREADS) {
+            let tx = tx.clone();
+            spawn(proc() {
+                for _ in range(0, NUM_ITERS) {
+                    let target = INSERTED.fetch_add(1, SeqCst) + 1;
+                    // Either flush everything inserted so far ourselves, or wait for
+                    // whoever is flushing and check whether they covered us.
+                    while FLUSHED.load(SeqCst) < target {
+                        if LOCK.acquire_or_wait(Exclusive) {
+                            if FLUSHED.load(SeqCst) < target {
+                                FLUSHED.store(INSERTED.load(SeqCst), SeqCst);
+                                FLUSHES.fetch_add(1, SeqCst);
+                            }
+                            unsafe { LOCK.release(); }
+                        }
+                    }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert_eq!(FLUSHED.load(SeqCst), NUM_THREADS * NUM_ITERS);
+        assert!(FLUSHES.load(SeqCst) <= NUM_THREADS * NUM_ITERS);
+    }
+
+    fn num_held_lwlocks() -> uint {
+        process::MY_PROC.with( |thread| thread.held_lwlocks.borrow().len())
+    }
+
+    #[test]
+    fn test_held_lwlocks() {
+        let a = LWLock::new(());
+        let b = LWLock::new(());
+        assert_eq!(num_held_lwlocks(), 0);
+        a.acquire(Exclusive);
+        assert!(b.try_acquire(Shared));
+        assert_eq!(num_held_lwlocks(), 2);
+        // Release out of order.
+        unsafe { a.release(); }
+        assert_eq!(num_held_lwlocks(), 1);
+        {
+            let _guard = a.lock_shared();
+            assert_eq!(num_held_lwlocks(), 2);
+        }
+        unsafe { b.release(); }
+        assert_eq!(num_held_lwlocks(), 0);
+        // Failing to get a lock doesn't remember it.
+        let _guard = a.lock_exclusive();
+        assert!(!a.try_acquire(Shared));
+        assert_eq!(num_held_lwlocks(), 1);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_release_not_held() {
+        let lock = LWLock::new(());
+        unsafe { lock.release(); }
+    }
+
+    #[test]
+    fn test_release_all() {
+        let a = LWLock::new(0u);
+        let b = LWLock::new(());
+        a.acquire(Exclusive);
+        b.acquire(Shared);
+        assert!(!a.try_acquire(Shared));
+        release_all();
+        assert_eq!(num_held_lwlocks(), 0);
+        assert!(a.try_lock_exclusive().is_some());
+        assert!(b.try_lock_exclusive().is_some());
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_guard_outlives_release_all() {
+        let lock = LWLock::new(());
+        let _guard = lock.lock_shared();
+        release_all();
+    }
+
+    #[test]
+    fn test_too_many_lwlocks() {
+        let locks = Arc::new(Vec::from_fn(MAX_SIMUL_LWLOCKS + 1, |_| LWLock::new(())));
+        let locks_ = locks.clone();
+        assert!(task::try(proc() {
+            for lock in locks_.iter() {
+                lock.acquire(Shared);
+            }
+        }).is_err());
+        // Everything the failed thread managed to take was released when it exited.
+        for lock in locks.iter() {
+            assert!(wait_for_lock(lock));
+        }
+    }
+
+    #[test]
+    fn test_unwind_releases_lwlocks() {
+        let lock = Arc::new(LWLock::new(()));
+        let lock_ = lock.clone();
+        assert!(task::try(proc() {
+            lock_.acquire(Exclusive);
+            panic!("error in critical section");
+        }).is_err());
+        assert!(wait_for_lock(&*lock));
+    }
+
+    fn queue_len<T>(lock: &LWLock<T>) -> uint where T: Send {
+        spin_lock_acquire!(guard = lock.mutex, {
+            let mut queued = 0u;
+            let mut proc_ = guard.deref().1.header.head;
+            loop {
+                match proc_ {
+                    Some(p) => { queued += 1; proc_ = p.get().lw_wait_link.get(); },
+                    None => break,
+                }
+            }
+            queued
+        })
+    }
+
+    #[test]
+    fn test_acquire_timeout() {
+        let lock = Arc::new(LWLock::new(()));
+        // Free lock: no waiting.
+        assert_eq!(lock.acquire_timeout(Exclusive, Duration::zero()), Ok(true));
+        let lock_ = lock.clone();
+        let result = task::try(proc() {
+            lock_.acquire_timeout(Shared, Duration::milliseconds(10))
+        });
+        assert_eq!(result.ok(), Some(Err(LWLockError::Timeout)));
+        assert_eq!(queue_len(&*lock), 0);
+
+        // A waiter that gets the lock in time.
+        let lock_ = lock.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let acquired = lock_.lock_exclusive_timeout(Duration::seconds(10)).is_ok();
+            tx.send(acquired);
+        });
+        while queue_len(&*lock) == 0 {
+            task::deschedule();
+        }
+        unsafe { lock.release(); }
+        assert!(rx.recv());
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert!(header.release_ok);
+        })
+    }
+
+    #[test]
+    fn test_cancel_wait() {
+        let lock = Arc::new(LWLock::new(()));
+        let _guard = lock.lock_exclusive();
+        let (tx, rx) = channel();
+        for _ in range(0, 2) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                tx.send(lock.acquire_timeout(Exclusive, Duration::seconds(60)));
+            });
+        }
+        while queue_len(&*lock) < 2 {
+            task::deschedule();
+        }
+        // Cancel the second waiter; the first one stays queued.
+        let second = spin_lock_acquire!(guard = lock.mutex, {
+            guard.deref().1.header.tail.unwrap().get()
+        });
+        second.cancel_lwlock_wait();
+        assert_eq!(rx.recv(), Err(LWLockError::Cancelled));
+        assert_eq!(queue_len(&*lock), 1);
+        let first = spin_lock_acquire!(guard = lock.mutex, {
+            guard.deref().1.header.head.unwrap().get()
+        });
+        first.cancel_lwlock_wait();
+        assert_eq!(rx.recv(), Err(LWLockError::Cancelled));
+        assert_eq!(queue_len(&*lock), 0);
+    }
+
+    #[test]
+    fn test_interrupt_wait() {
+        let lock = Arc::new(LWLock::new(()));
+        let _guard = lock.lock_exclusive();
+        let (tx, rx) = channel();
+        let lock_ = lock.clone();
+        spawn(proc() {
+            tx.send(task::try(proc() {
+                let _ = lock_.acquire_timeout(Exclusive, Duration::seconds(60));
+            }).is_err());
+        });
+        while queue_len(&*lock) < 1 {
+            task::deschedule();
+        }
+        let waiter = spin_lock_acquire!(guard = lock.mutex, {
+            guard.deref().1.header.head.unwrap().get()
+        });
+        waiter.signal(process::ProcSignal::Cancel);
+        assert!(rx.recv());
+        assert_eq!(queue_len(&*lock), 0);
+    }
+
+    #[test]
+    fn test_interrupt_held_off() {
+        let lock = Arc::new(LWLock::new(()));
+        let _guard = lock.lock_exclusive();
+        let (tx, rx) = channel();
+        let lock_ = lock.clone();
+        spawn(proc() {
+            let tx_ = tx.clone();
+            let interrupted = task::try(proc() {
+                let other = LWLock::new(());
+                let result = {
+                    // Holding another LWLock puts us in a critical section.
+                    let _other = other.lock_shared();
+                    lock_.acquire_timeout(Exclusive, Duration::milliseconds(100))
+                };
+                tx_.send(result);
+                // Now we can take the interrupt.
+                process::check_for_interrupts();
+            }).is_err();
+            tx.send(Ok(interrupted));
+        });
+        while queue_len(&*lock) < 1 {
+            task::deschedule();
+        }
+        let waiter = spin_lock_acquire!(guard = lock.mutex, {
+            guard.deref().1.header.head.unwrap().get()
+        });
+        waiter.signal(process::ProcSignal::Cancel);
+        assert_eq!(rx.recv(), Err(LWLockError::Timeout));
+        assert_eq!(rx.recv(), Ok(true));
+    }
+
+    #[test]
+    fn test_timeout_contended() {
+        static SUCCESSES: AtomicUint = INIT_ATOMIC_UINT;
+        let lock = Arc::new(LWLock::new(0u));
+        let (tx, rx) = channel();
+        for i in range(0, NUM_THREADS) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                for _ in range(0, NUM_ITERS) {
+                    // Some threads wait with no timeout, so that timed-out waiters have
+                    // somebody to hand wakeups on to.
+                    if i % 2 == 0 {
+                        *lock.lock_exclusive() += 1;
+                        SUCCESSES.fetch_add(1, SeqCst);
+                    } else {
+                        match lock.lock_exclusive_timeout(Duration::microseconds(10)) {
+                            Ok(mut guard) => {
+                                *guard += 1;
+                                SUCCESSES.fetch_add(1, SeqCst);
+                            },
+                            Err(err) => assert_eq!(err, LWLockError::Timeout),
+                        }
+                    }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert_eq!(*lock.lock_shared(), SUCCESSES.load(SeqCst));
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+            assert!(header.head.is_none());
+            assert!(header.release_ok);
+        })
+    }
+
+    /// The holder's cleanup runs as its thread exits, so give it a moment.
+    fn wait_for_lock(lock: &LWLock<()>) -> bool {
+        for _ in range(0, 1000u) {
+            match lock.try_lock_exclusive() {
+                Some(_) => return true,
+                None => timer::sleep(Duration::milliseconds(1)),
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn test_var() {
+        let lock = LWLock::with_var((), 0);
+        // Free lock: nothing to wait for.
+        assert_eq!(lock.wait_for_var(0), None);
+        assert!(lock.acquire_with_var(5));
+        assert_eq!(lock.wait_for_var(0), Some(5));
+        lock.update_var(6);
+        assert_eq!(lock.wait_for_var(5), Some(6));
+        unsafe { lock.release(); }
+        assert_eq!(lock.wait_for_var(6), None);
+    }
+
+    #[test]
+    fn test_var_contended() {
+        let lock = Arc::new(LWLock::with_var((), 0));
+        assert!(lock.acquire_with_var(1));
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_THREADS) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                // Follow the holder's progress until it lets go of the lock.
+                let mut seen = 1;
+                loop {
+                    match lock.wait_for_var(seen) {
+                        Some(value) => {
+                            assert!(value > seen);
+                            seen = value;
+                        },
+                        None => break,
+                    }
+                }
+                tx.send(seen);
+            });
+        }
+        for i in range(2, NUM_ITERS as u64) {
+            lock.update_var(i);
+            task::deschedule();
+        }
+        unsafe { lock.release(); }
+        for _ in range(0, NUM_THREADS) {
+            assert!(rx.recv() < NUM_ITERS as u64);
+        }
+        // Nobody took the lock.
+        assert!(lock.try_acquire(Exclusive));
+        unsafe { lock.release(); }
+    }
+
+    #[test]
+    fn test_guards_contended() {
+        let lock = Arc::new(LWLock::new(0u));
+        let (tx, rx) = channel();
+        for i in range(0, NUM_THREADS) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                for j in range(0, NUM_ITERS) {
+                    if (i + j) % 2 == 0 {
+                        let mut guard = lock.lock_exclusive();
+                        let count = *guard;
+                        task::deschedule();
+                        *guard = count + 1;
+                    } else {
+                        let guard = lock.lock_shared();
+                        assert!(*guard <= NUM_THREADS * NUM_ITERS / 2);
+                    }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert_eq!(*lock.lock_shared(), NUM_THREADS * NUM_ITERS / 2);
+    }
+
+    #[test]
+    fn test_exclusive_contended() {
+        let lock = Arc::new(LWLock::new(0u));
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_THREADS) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                for _ in range(0, NUM_ITERS) {
+                    lock.acquire(Exclusive);
+                    unsafe {
+                        // Deliberately non-atomic read-modify-write.
+                        let count = *data(&*lock);
+                        task::deschedule();
+                        *data(&*lock) = count + 1;
+                        lock.release();
+                    }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert_eq!(unsafe { *data(&*lock) }, NUM_THREADS * NUM_ITERS);
+    }
+
+    #[test]
+    fn test_mixed_contended() {
+        static LOCK: LWLock<uint> = lwlock_init!(0);
+        static EXCLUSIVE_HOLDERS: AtomicUint = INIT_ATOMIC_UINT;
+        static SHARED_HOLDERS: AtomicUint = INIT_ATOMIC_UINT;
+
+        let (tx, rx) = channel();
+        for i in range(0, NUM_THREADS) {
+            let tx = tx.clone();
+            spawn(proc() {
+                for j in range(0, NUM_ITERS) {
+                    if (i + j) % 4 == 0 {
+                        LOCK.acquire(Exclusive);
+                        assert_eq!(EXCLUSIVE_HOLDERS.fetch_add(1, SeqCst), 0);
+                        assert_eq!(SHARED_HOLDERS.load(SeqCst), 0);
+                        unsafe { *data(&LOCK) += 1; }
+                        task::deschedule();
+                        EXCLUSIVE_HOLDERS.fetch_sub(1, SeqCst);
+                    } else {
+                        LOCK.acquire(Shared);
+                        SHARED_HOLDERS.fetch_add(1, SeqCst);
+                        assert_eq!(EXCLUSIVE_HOLDERS.load(SeqCst), 0);
+                        task::deschedule();
+                        SHARED_HOLDERS.fetch_sub(1, SeqCst);
+                    }
+                    unsafe { LOCK.release(); }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert_eq!(unsafe { *data(&LOCK) }, NUM_THREADS * NUM_ITERS / 4);
+        spin_lock_acquire!(guard = LOCK.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+            assert!(header.head.is_none());
+        })
+    }
+
+    #[test]
+    fn test_fair_queues_newcomers() {
+        let lock = Arc::new(LWLock::new(0u).fair());
+        assert!(lock.is_fair());
+        let guard = lock.lock_shared();
+        let (tx, rx) = channel();
+        {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                *lock.lock_exclusive() += 1;
+                tx.send(());
+            });
+        }
+        while queue_len(&*lock) < 1 {
+            task::deschedule();
+        }
+        // The lock is only held shared, but a shared newcomer must not overtake the queued
+        // exclusive waiter.
+        {
+            let lock = lock.clone();
+            assert!(task::try(proc() {
+                lock.try_lock_shared().is_none()
+            }).unwrap());
+        }
+        {
+            let lock = lock.clone();
+            spawn(proc() {
+                assert_eq!(*lock.lock_shared(), 1);
+                tx.send(());
+            });
+        }
+        while queue_len(&*lock) < 2 {
+            task::deschedule();
+        }
+        drop(guard);
+        rx.recv();
+        rx.recv();
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+            assert_eq!(header.lock_waiters, 0);
+            assert!(header.head.is_none());
+        })
+    }
+
+    #[test]
+    fn test_fair_nested_shared() {
+        // The exclusive waiter is waiting for us, so we mustn't queue behind it when we take the
+        // lock shared again.
+        let lock = Arc::new(LWLock::new(()).fair());
+        let guard = lock.lock_shared();
+        let (tx, rx) = channel();
+        {
+            let lock = lock.clone();
+            spawn(proc() {
+                drop(lock.lock_exclusive());
+                tx.send(());
+            });
+        }
+        while queue_len(&*lock) < 1 {
+            task::deschedule();
+        }
+        let nested = lock.lock_shared();
+        assert_eq!(queue_len(&*lock), 1);
+        drop(nested);
+        drop(guard);
+        rx.recv();
+        assert!(lock.try_lock_exclusive().is_some());
+    }
+
+    #[test]
+    fn test_fair_timeout_hands_off() {
+        let lock = Arc::new(LWLock::new(()).fair());
+        let guard = lock.lock_shared();
+        let (tx, rx) = channel();
+        {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                tx.send(lock.acquire_timeout(Exclusive, Duration::seconds(60)));
+            });
+        }
+        while queue_len(&*lock) < 1 {
+            task::deschedule();
+        }
+        {
+            let lock = lock.clone();
+            spawn(proc() {
+                let acquired = lock.lock_shared_timeout(Duration::seconds(60)).is_ok();
+                tx.send(Ok(acquired));
+            });
+        }
+        while queue_len(&*lock) < 2 {
+            task::deschedule();
+        }
+        // Once the exclusive waiter gives up, the shared waiter it was holding up can join us.
+        let first = spin_lock_acquire!(guard = lock.mutex, {
+            guard.deref().1.header.head.unwrap().get()
+        });
+        first.cancel_lwlock_wait();
+        assert_eq!(rx.recv(), Err(LWLockError::Cancelled));
+        assert_eq!(rx.recv(), Ok(true));
+        drop(guard);
+        assert!(lock.try_lock_exclusive().is_some());
+    }
+
+    #[test]
+    fn test_fair_bounded_exclusive_wait() {
+        // Readers overlap each other continuously, so without fairness the writer could be
+        // kept waiting indefinitely.
+        static DONE: AtomicBool = INIT_ATOMIC_BOOL;
+        const NUM_WRITES: uint = 100;
+
+        let lock = Arc::new(LWLock::new(0u).fair());
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_THREADS - 1) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                while !DONE.load(SeqCst) {
+                    let _guard = lock.lock_shared();
+                    for _ in range(0, 100u) {
+                        task::deschedule();
+                    }
+                }
+                tx.send(());
+            });
+        }
+        let mut max_wait = 0;
+        for _ in range(0, NUM_WRITES) {
+            let start = time::precise_time_ns();
+            let mut guard = lock.lock_exclusive();
+            max_wait = cmp::max(max_wait, time::precise_time_ns() - start);
+            *guard += 1;
+        }
+        DONE.store(true, SeqCst);
+        for _ in range(0, NUM_THREADS - 1) {
+            rx.recv();
+        }
+        assert!(max_wait < 1_000_000_000, "writer waited {}ns for a fair lock", max_wait);
+        assert_eq!(*lock.lock_shared(), NUM_WRITES);
+        spin_lock_acquire!(guard = lock.mutex, {
+            let header = &guard.deref().1.header;
+            assert!(!header.exclusive);
+            assert_eq!(header.shared, 0);
+            assert_eq!(header.lock_waiters, 0);
+            assert!(header.head.is_none());
+        })
+    }
+
+    #[test]
+    fn lwlock_padded_size() {
+        assert_eq!(LWLOCK_PADDED_SIZE, mem::size_of::<LWLock<()>>() + LWLOCK_PADDING);
+        assert_eq!(LWLOCK_PADDED_SIZE, mem::size_of::<LWLockPadded>());
+        assert_eq!(LWLOCK_PADDED_SIZE * NUM_INDIVIDUAL_LWLOCKS, mem::size_of_val(&MAIN_LWLOCK_ARRAY));
+    }
+
+    #[test]
+    fn test_main_lwlocks() {
+        assert!(buf_freelist_lock() as *const _ == &MAIN_LWLOCK_ARRAY[0].lock as *const _);
+        assert!(proc_array_lock() as *const _ == &MAIN_LWLOCK_ARRAY[4].lock as *const _);
+        assert!(commit_ts_lock() as *const _ ==
+                &MAIN_LWLOCK_ARRAY[NUM_INDIVIDUAL_LWLOCKS - 1].lock as *const _);
+        for lock in MAIN_LWLOCK_ARRAY.iter() {
+            assert_eq!(lock.tranche(), LWTRANCHE_MAIN);
+        }
+
+        let _guard = xid_gen_lock().lock_exclusive();
+        assert!(xid_gen_lock().try_lock_shared().is_none());
+        assert!(oid_gen_lock().try_lock_exclusive().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "lwlock-stats")]
+    fn test_stats() {
+        let tranche = new_tranche_id();
+        let lock = LWLock::new(()).in_tranche(tranche);
+        assert_eq!(lock.stats(), LWLOCK_STATS_INIT);
+
+        drop(lock.lock_shared());
+        drop(lock.lock_exclusive());
+        assert!(lock.try_acquire(Shared));
+        assert!(lock.acquire_or_wait(Shared));
+        assert!(!lock.try_acquire(Exclusive));
+        unsafe {
+            lock.release();
+            lock.release();
+        }
+
+        let stats = lock.stats();
+        assert_eq!(stats.sh_acquire_count, 3);
+        assert_eq!(stats.ex_acquire_count, 1);
+        assert_eq!(stats.block_count, 0);
+        assert_eq!(stats.block_time_ns, 0);
+        assert_eq!(tranche_stats(tranche), stats);
+
+        // Resetting a lock doesn't reset its tranche, and vice versa.
+        lock.reset_stats();
+        assert_eq!(lock.stats(), LWLOCK_STATS_INIT);
+        assert_eq!(tranche_stats(tranche), stats);
+        drop(lock.lock_exclusive());
+        reset_tranche_stats(tranche);
+        assert_eq!(tranche_stats(tranche), LWLOCK_STATS_INIT);
+        assert_eq!(lock.stats().ex_acquire_count, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "lwlock-stats")]
+    fn test_stats_blocked() {
+        let lock = Arc::new(LWLock::new(()).in_tranche(new_tranche_id()));
+        lock.acquire(Exclusive);
+        let (tx, rx) = channel();
+        for i in range(0, NUM_THREADS) {
+            let lock = lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                if i % 2 == 0 {
+                    drop(lock.lock_shared());
+                } else {
+                    assert!(!lock.acquire_or_wait(Exclusive));
+                }
+                tx.send(());
+            });
+        }
+        // Wait until everyone is queued.
+        while queue_len(&*lock) != NUM_THREADS {
+            task::deschedule();
+        }
+        timer::sleep(Duration::milliseconds(10));
+        unsafe { lock.release(); }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+
+        let stats = lock.stats();
+        assert_eq!(stats.ex_acquire_count, 1);
+        assert_eq!(stats.sh_acquire_count, (NUM_THREADS / 2) as u64);
+        assert_eq!(stats.block_count, NUM_THREADS as u64);
+        assert!(stats.block_time_ns >= 10 * 1000 * 1000 * NUM_THREADS as u64, "{}", stats);
+        assert_eq!(tranche_stats(lock.tranche()), stats);
+    }
 }
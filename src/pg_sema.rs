@@ -0,0 +1,153 @@
+// Counting semaphores that threads sleep on while waiting for a lock.
+//
+// This is `std::sync::Semaphore` with the addition of a bounded wait, built the same way from
+// a mutex and a condition variable.
+
+use std::cmp;
+use std::i64;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+use time;
+
+/// The `time::precise_time_ns` value `timeout` from now.  A negative timeout counts as zero,
+/// and one too long to count in nanoseconds as i64::MAX of them.
+pub fn deadline_after(timeout: Duration) -> u64 {
+    time::precise_time_ns() + cmp::max(timeout.num_nanoseconds().unwrap_or(i64::MAX), 0) as u64
+}
+
+pub struct PGSemaphore {
+    count: Mutex<int>,
+    cvar: Condvar,
+}
+
+impl PGSemaphore {
+    pub fn new(count: int) -> PGSemaphore {
+        PGSemaphore {
+            count: Mutex::new(count),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Decrement the semaphore, sleeping until it is positive first.
+    pub fn acquire(&self) {
+        let mut count = self.count.lock();
+        while *count <= 0 {
+            self.cvar.wait(&count);
+        }
+        *count -= 1;
+    }
+
+    /// Like `acquire`, but give up after `timeout`.  Returns true if the semaphore was
+    /// decremented, false if we timed out.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.acquire_interruptible(timeout, None)
+    }
+
+    /// Like `acquire_timeout`, but also give up (returning false) once `interrupted` is set.
+    /// Whoever sets it must then call `interrupt`, so that we notice.
+    pub fn acquire_interruptible(&self, timeout: Duration,
+                                 interrupted: Option<&AtomicBool>) -> bool {
+        let deadline = deadline_after(timeout);
+        let mut count = self.count.lock();
+        while *count <= 0 {
+            match interrupted {
+                Some(flag) if flag.load(SeqCst) => return false,
+                _ => {},
+            }
+            let now = time::precise_time_ns();
+            if now >= deadline {
+                return false
+            }
+            // Wakeups may be spurious, so recheck the count (and the time) either way.
+            self.cvar.wait_timeout(&count, Duration::nanoseconds((deadline - now) as i64));
+        }
+        *count -= 1;
+        true
+    }
+
+    /// Increment the semaphore, waking a sleeper if there is one.
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.cvar.notify_one();
+    }
+
+    /// Wake anyone sleeping in `acquire_interruptible` to recheck their flag, without
+    /// incrementing the semaphore.
+    pub fn interrupt(&self) {
+        // Taking the mutex means a sleeper either saw the flag already, or is waiting and
+        // gets this notification.
+        let _count = self.count.lock();
+        self.cvar.notify_all();
+    }
+
+    /// Set the count back to zero, e.g. before handing the semaphore to a new owner.
+    pub fn reset(&self) {
+        *self.count.lock() = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PGSemaphore;
+
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::time::Duration;
+
+    #[test]
+    fn test_acquire_release() {
+        let sem = PGSemaphore::new(1);
+        sem.acquire();
+        sem.release();
+        sem.acquire();
+    }
+
+    #[test]
+    fn test_acquire_timeout() {
+        let sem = PGSemaphore::new(0);
+        assert!(!sem.acquire_timeout(Duration::milliseconds(10)));
+        sem.release();
+        assert!(sem.acquire_timeout(Duration::milliseconds(10)));
+        assert!(!sem.acquire_timeout(Duration::zero()));
+    }
+
+    #[test]
+    fn test_wakeup() {
+        let sem = Arc::new(PGSemaphore::new(0));
+        let sem_ = sem.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            sem_.acquire();
+            tx.send(sem_.acquire_timeout(Duration::seconds(10)));
+        });
+        sem.release();
+        sem.release();
+        assert!(rx.recv());
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let sem = Arc::new(PGSemaphore::new(0));
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let (sem_, interrupted_) = (sem.clone(), interrupted.clone());
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(sem_.acquire_interruptible(Duration::seconds(60), Some(&*interrupted_)));
+        });
+        interrupted.store(true, SeqCst);
+        sem.interrupt();
+        assert!(!rx.recv());
+        // The count wasn't touched.
+        assert!(!sem.acquire_timeout(Duration::zero()));
+    }
+
+    #[test]
+    fn test_reset() {
+        let sem = PGSemaphore::new(2);
+        sem.reset();
+        assert!(!sem.acquire_timeout(Duration::zero()));
+    }
+}
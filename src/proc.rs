@@ -1,46 +1,519 @@
 /// Per-thread shared memory data structures
 
 use lwlock::{
+    mod,
+    HeldLWLock,
+    LWLock,
     LWLockMode,
+    MAX_SIMUL_LWLOCKS,
 };
 
-use std::cell::Cell;
+use latch::Latch;
+use lock::{mod, FastPathLocks, LocalLock, LockMask, LockMode, LockTag};
+use pg_sema::PGSemaphore;
+use procarray;
+use s_lock::SpinLock;
+use trans::TransactionId;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fmt;
-use std::sync::Semaphore;
-use std::thread_local::Key;
+use std::mem;
+use std::sync::{Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, AtomicUint};
+use std::sync::atomic::Ordering::SeqCst;
+
+// Every `Proc` lives in one fixed array, allocated the first time a thread asks for its `Proc`,
+// so that other threads can find a `Proc` from its number (`proc_by_number`).  A thread takes
+// a free `Proc` when it starts and gives it back when it exits, after taking it out of the
+// ProcArray (see `procarray`).
+
+/// The most threads that can have a `Proc` at once.
+pub const MAX_BACKENDS: uint = 1024;
+
+/// The index of a `Proc` in the array of all Procs.  Shared structures such as wait queues
+/// link Procs by number, and any thread can follow the link with `get`.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Show)]
+pub struct ProcNumber(pub u32);
+
+impl ProcNumber {
+    /// The `Proc` with this number.
+    #[inline]
+    pub fn get(self) -> &'static Proc {
+        proc_by_number(self)
+    }
+
+    #[inline]
+    pub fn to_uint(self) -> uint {
+        self.0 as uint
+    }
+}
 
 #[repr(C)]
 pub struct Proc {
-    // SHM_QUEUE   links,          /* list link if process is in a list */
+    /// next free Proc, if this one is on the free list
+    pub links: Cell<Option<ProcNumber>>,
+    /// this Proc's index in the array of all Procs
+    pub pgprocno: ProcNumber,
 
     /// ONE semaphore to sleep on
-    pub sem: Semaphore,
+    pub sem: PGSemaphore,
+    /// generic latch for other threads to wake this one (see `latch`)
+    pub latch: Latch,
 
     // Info about LWLock the process is currently waiting for, if any.
     /// true if waiting for an LW lock
     pub lw_waiting: Cell<bool>,
     /// lwlock mode being waited for
     pub lw_wait_mode: Cell<LWLockMode>,
+    /// tranche of the lwlock being waited for
+    pub lw_wait_tranche: Cell<u32>,
     /// next waiter for same LW lock
-    pub lw_wait_link: Cell<Option<&'static Key<Proc>>>,
+    pub lw_wait_link: Cell<Option<ProcNumber>>,
+    /// true if another thread asked us to give up a cancellable LWLock wait
+    pub lw_wait_cancel: AtomicBool,
+    /// true if a releaser handed us the (fair) lock we were waiting for
+    pub lw_granted: Cell<bool>,
+
+    // Info about the condition variable the process is waiting on, if any.
+    /// true if on a condition variable's wait list
+    pub cv_waiting: Cell<bool>,
+    /// next waiter for the same condition variable
+    pub cv_wait_link: Cell<Option<ProcNumber>>,
+
+    /// LWLocks held by this thread, in acquisition order
+    pub held_lwlocks: RefCell<Vec<HeldLWLock>>,
+
+    // Info about the heavyweight lock the process is waiting for, if any.  These are protected
+    // by the lock's partition LWLock.
+    /// true if on a heavyweight lock's wait queue
+    pub lock_waiting: Cell<bool>,
+    /// the lock being waited for
+    pub wait_lock: Cell<Option<LockTag>>,
+    /// mode of the lock being waited for
+    pub wait_lock_mode: Cell<LockMode>,
+    /// modes of the awaited lock we already held when we started waiting
+    pub held_locks: Cell<LockMask>,
+
+    /// weak relation locks taken through the fast path
+    pub fp_locks: LWLock<FastPathLocks>,
+    /// heavyweight locks held by this thread; only the thread itself looks at these
+    pub local_locks: RefCell<HashMap<(LockTag, LockMode), LocalLock>>,
+
+    // Interrupts sent by other threads (see `signal`), and whether we can take them now.
+    /// true if any of the interrupts below is pending
+    pub interrupt_pending: AtomicBool,
+    /// true if the current query should be cancelled
+    pub query_cancel_pending: AtomicBool,
+    /// true if the thread should exit
+    pub proc_die_pending: AtomicBool,
+    /// true if the current statement has run out of time
+    pub timeout_pending: AtomicBool,
+    /// interrupts are held off while this is nonzero; only the thread itself changes it
+    pub interrupt_holdoff_count: Cell<u32>,
+
+    // Transaction state, which other threads read through the ProcArray.
+    /// top-level transaction being run by this thread, or invalid
+    xid: AtomicUint,
+    /// oldest xid this thread's snapshots consider running, or invalid
+    xmin: AtomicUint,
+}
+
+/// Interrupts that one thread can send another's `Proc`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ProcSignal {
+    /// Cancel the current query.
+    Cancel,
+    /// Make the thread exit.
+    Terminate,
+    /// The current statement has run out of time.
+    Timeout,
+}
+
+/// Process-wide `Proc` bookkeeping, protected by `PROC_GLOBAL`.
+struct ProcGlobal {
+    /// head of the list of free Procs, which are handed out oldest first so that a number
+    /// isn't reused as soon as its last owner gives it back
+    free_procs: Option<ProcNumber>,
+    /// tail of the list of free Procs
+    free_procs_tail: Option<ProcNumber>,
+}
+
+static PROC_GLOBAL: SpinLock<(), ProcGlobal> = spin_lock_init!((), ProcGlobal {
+    free_procs: None,
+    free_procs_tail: None,
+});
+
+/// The array of all `MAX_BACKENDS` Procs, allocated by `init_proc_global` and never freed.
+static mut ALL_PROCS: *const Proc = 0 as *const Proc;
+static ALL_PROCS_INIT: Once = ONCE_INIT;
+
+/// This thread's Proc, or null if it hasn't got one yet (or has given it back).  It is a
+/// plain pointer rather than a `thread_local!` so that it is still usable while the thread's
+/// other thread-locals are being destroyed.
+#[thread_local]
+static mut MY_PROC_PTR: *const Proc = 0 as *const Proc;
+
+fn init_proc_global() {
+    let mut procs = Vec::with_capacity(MAX_BACKENDS);
+    for i in range(0, MAX_BACKENDS) {
+        let proc_ = Proc::new(ProcNumber(i as u32));
+        proc_.links.set(if i + 1 < MAX_BACKENDS { Some(ProcNumber(i as u32 + 1)) } else { None });
+        procs.push(proc_);
+    }
+    unsafe {
+        ALL_PROCS = procs.as_ptr();
+        mem::forget(procs);
+    }
+    spin_lock_acquire!(mut guard = PROC_GLOBAL, {
+        let global = guard.deref_mut().1;
+        global.free_procs = Some(ProcNumber(0));
+        global.free_procs_tail = Some(ProcNumber(MAX_BACKENDS as u32 - 1));
+    })
+}
+
+/// Returns the `Proc` with the given number.  This works from any thread, whether or not the
+/// `Proc` belongs to it.
+#[inline]
+pub fn proc_by_number(pgprocno: ProcNumber) -> &'static Proc {
+    assert!(pgprocno.to_uint() < MAX_BACKENDS);
+    ALL_PROCS_INIT.doit(init_proc_global);
+    unsafe { &*ALL_PROCS.offset(pgprocno.to_uint() as int) }
+}
+
+/// Take a free Proc for this thread, and add it to the ProcArray (PostgreSQL's InitProcess).
+#[cold] #[inline(never)]
+fn init_process() {
+    ALL_PROCS_INIT.doit(init_proc_global);
+
+    let pgprocno = spin_lock_acquire!(mut guard = PROC_GLOBAL, {
+        let global = guard.deref_mut().1;
+        match global.free_procs {
+            Some(pgprocno) => {
+                global.free_procs = proc_by_number(pgprocno).links.get();
+                if global.free_procs.is_none() {
+                    global.free_procs_tail = None;
+                }
+                pgprocno
+            },
+            None => panic!("sorry, too many clients already"),
+        }
+    });
+
+    // Reset anything the Proc's last owner left behind.
+    let proc_ = proc_by_number(pgprocno);
+    proc_.links.set(None);
+    proc_.sem.reset();
+    proc_.latch.reset();
+    proc_.lw_waiting.set(false);
+    proc_.lw_wait_link.set(None);
+    proc_.lw_wait_cancel.store(false, SeqCst);
+    proc_.lw_granted.set(false);
+    proc_.cv_waiting.set(false);
+    proc_.cv_wait_link.set(None);
+    {
+        let mut held_lwlocks = proc_.held_lwlocks.borrow_mut();
+        debug_assert!(held_lwlocks.is_empty())
+        held_lwlocks.reserve(MAX_SIMUL_LWLOCKS);
+    }
+    proc_.lock_waiting.set(false);
+    proc_.wait_lock.set(None);
+    proc_.held_locks.set(0);
+    debug_assert!(proc_.local_locks.borrow().is_empty())
+    proc_.interrupt_pending.store(false, SeqCst);
+    proc_.query_cancel_pending.store(false, SeqCst);
+    proc_.proc_die_pending.store(false, SeqCst);
+    proc_.timeout_pending.store(false, SeqCst);
+    proc_.interrupt_holdoff_count.set(0);
+    proc_.set_xid(TransactionId::invalid());
+    proc_.set_xmin(TransactionId::invalid());
+
+    unsafe { MY_PROC_PTR = proc_ as *const Proc; }
+    // Arrange to give the Proc back when the thread exits.
+    PROC_EXIT.with( |_| ());
+
+    // Now other threads can find us.
+    procarray::add(proc_);
+}
+
+/// Gives this thread's Proc back when the thread exits (PostgreSQL's ProcKill).
+struct ProcExit;
+
+impl Drop for ProcExit {
+    fn drop(&mut self) {
+        let proc_ = my_proc();
+
+        // If the thread unwound out of a critical section, nothing else is going to release the
+        // LWLocks it held.
+        lwlock::release_all_held(proc_);
+
+        // Any transaction the thread was running is over.
+        lock::release_all();
+
+        procarray::remove(proc_);
+
+        unsafe { MY_PROC_PTR = 0 as *const Proc; }
+        spin_lock_acquire!(mut guard = PROC_GLOBAL, {
+            let global = guard.deref_mut().1;
+            proc_.links.set(None);
+            match global.free_procs_tail {
+                Some(tail) => tail.get().links.set(Some(proc_.pgprocno)),
+                None => global.free_procs = Some(proc_.pgprocno),
+            }
+            global.free_procs_tail = Some(proc_.pgprocno);
+        })
+    }
+}
+
+thread_local!(static PROC_EXIT: ProcExit = ProcExit)
+
+impl Proc {
+    fn new(pgprocno: ProcNumber) -> Proc {
+        Proc {
+            links: Cell::new(None),
+            pgprocno: pgprocno,
+            sem: PGSemaphore::new(0),
+            latch: Latch::new(),
+            lw_waiting: Cell::new(false),
+            lw_wait_mode: Cell::new(LWLockMode::WaitUntilFree),
+            lw_wait_tranche: Cell::new(lwlock::LWTRANCHE_MAIN),
+            lw_wait_link: Cell::new(None),
+            lw_wait_cancel: AtomicBool::new(false),
+            lw_granted: Cell::new(false),
+            cv_waiting: Cell::new(false),
+            cv_wait_link: Cell::new(None),
+            held_lwlocks: RefCell::new(Vec::new()),
+            lock_waiting: Cell::new(false),
+            wait_lock: Cell::new(None),
+            wait_lock_mode: Cell::new(LockMode::AccessShare),
+            held_locks: Cell::new(0),
+            fp_locks: LWLock::new(FastPathLocks::new()).in_tranche(lock::fast_path_tranche()),
+            local_locks: RefCell::new(HashMap::new()),
+            interrupt_pending: AtomicBool::new(false),
+            query_cancel_pending: AtomicBool::new(false),
+            proc_die_pending: AtomicBool::new(false),
+            timeout_pending: AtomicBool::new(false),
+            interrupt_holdoff_count: Cell::new(0),
+            xid: AtomicUint::new(0),
+            xmin: AtomicUint::new(0),
+        }
+    }
+
+    /// The next Proc in the LWLock wait queue this one is in, if any.
+    #[inline]
+    pub fn lw_wait_next(&self) -> Option<&'static Proc> {
+        self.lw_wait_link.get().map( |next| next.get())
+    }
+
+    /// The top-level transaction this thread is running, or an invalid ID if none.
+    #[inline]
+    pub fn xid(&self) -> TransactionId {
+        TransactionId::from_u32(self.xid.load(SeqCst) as u32)
+    }
+
+    /// Only the thread itself should set its xid, holding ProcArrayLock in shared mode to
+    /// assign one and exclusive mode to clear it.
+    #[inline]
+    pub fn set_xid(&self, xid: TransactionId) {
+        self.xid.store(xid.to_u32() as uint, SeqCst);
+    }
+
+    /// The oldest transaction this thread's snapshots consider running, or an invalid ID if
+    /// it has no snapshot.  Nothing older than this can become invisible to it.
+    #[inline]
+    pub fn xmin(&self) -> TransactionId {
+        TransactionId::from_u32(self.xmin.load(SeqCst) as u32)
+    }
+
+    #[inline]
+    pub fn set_xmin(&self, xmin: TransactionId) {
+        self.xmin.store(xmin.to_u32() as uint, SeqCst);
+    }
+
+    /// Send this thread an interrupt.  It is processed the next time the thread checks for
+    /// interrupts outside a critical section, or straight away if the thread is sleeping in
+    /// an interruptible wait.
+    pub fn signal(&self, signal: ProcSignal) {
+        match signal {
+            ProcSignal::Cancel => self.query_cancel_pending.store(true, SeqCst),
+            ProcSignal::Terminate => self.proc_die_pending.store(true, SeqCst),
+            ProcSignal::Timeout => self.timeout_pending.store(true, SeqCst),
+        }
+        self.interrupt_pending.store(true, SeqCst);
+        self.sem.interrupt();
+        // Wake the thread if it is waiting on its latch, so that it can check for interrupts.
+        self.latch.set();
+    }
+
+    /// Hold off interrupts until the matching `resume_interrupts`.  Calls nest.
+    #[inline]
+    pub fn hold_interrupts(&self) {
+        self.interrupt_holdoff_count.set(self.interrupt_holdoff_count.get() + 1);
+    }
+
+    #[inline]
+    pub fn resume_interrupts(&self) {
+        debug_assert!(self.interrupt_holdoff_count.get() > 0)
+        self.interrupt_holdoff_count.set(self.interrupt_holdoff_count.get() - 1);
+    }
+
+    /// Process any pending interrupts, unless they are held off.  Cancelling and terminating
+    /// both panic; a thread that catches the panic can carry on with its next query.
+    #[inline]
+    pub fn check_for_interrupts(&self) {
+        if self.interrupt_pending.load(SeqCst) {
+            self.process_interrupts();
+        }
+    }
+
+    #[cold] #[inline(never)]
+    fn process_interrupts(&self) {
+        // OK to accept any interrupts now?
+        if self.interrupt_holdoff_count.get() != 0 {
+            return
+        }
+        self.interrupt_pending.store(false, SeqCst);
+        if self.proc_die_pending.swap(false, SeqCst) {
+            self.query_cancel_pending.store(false, SeqCst);
+            self.timeout_pending.store(false, SeqCst);
+            panic!("terminating thread due to administrator command");
+        }
+        if self.timeout_pending.swap(false, SeqCst) {
+            self.query_cancel_pending.store(false, SeqCst);
+            panic!("canceling statement due to statement timeout");
+        }
+        if self.query_cancel_pending.swap(false, SeqCst) {
+            panic!("canceling statement due to user request");
+        }
+    }
+
+    /// Ask this thread to give up the LWLock wait it is sleeping in, if that wait is
+    /// cancellable (see `LWLock::acquire_timeout`).  The wait returns
+    /// `LWLockError::Cancelled`.
+    ///
+    /// If the thread isn't in a cancellable wait, the request stays pending and cancels the
+    /// next cancellable wait that has to sleep.
+    pub fn cancel_lwlock_wait(&self) {
+        // Only wake the thread once per request, so that it doesn't see extra wakeups.
+        if !self.lw_wait_cancel.swap(true, SeqCst) {
+            self.sem.release();
+        }
+    }
 }
 
 impl fmt::Show for Proc {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Proc {{ \
+                  pgprocno: {}, \
+                  xid: {}, \
+                  xmin: {}, \
                   lw_waiting: {}, \
                   lw_wait_mode: {}, \
+                  lw_wait_tranche: {}, \
                   lw_wait_link: {} }}",
+                  self.pgprocno,
+                  self.xid(),
+                  self.xmin(),
                   self.lw_waiting,
                   self.lw_wait_mode,
-                  self.lw_wait_link.get().map( |p| p as *const _),
+                  lwlock::tranche_name(self.lw_wait_tranche.get()).unwrap_or("unknown"),
+                  self.lw_wait_link.get(),
         )
     }
 }
 
-thread_local!(pub static MY_PROC: Proc = Proc {
-    sem: Semaphore::new(1),
-    lw_waiting: Cell::new(false),
-    lw_wait_mode: Cell::new(LWLockMode::WaitUntilFree),
-    lw_wait_link: Cell::new(None),
-})
+/// This thread's `Proc`, used like a `thread_local!` key: `MY_PROC.with( |proc_| ...)`.
+pub struct LocalProc;
+
+pub static MY_PROC: LocalProc = LocalProc;
+
+impl LocalProc {
+    #[inline]
+    pub fn with<R>(&'static self, f: |&'static Proc| -> R) -> R {
+        f(my_proc())
+    }
+}
+
+/// Returns this thread's `Proc`, taking a free one the first time it is called.
+///
+/// A thread cannot exit while it is sleeping in a wait queue, so it holds on to its `Proc` as
+/// long as other threads hold links to it.
+#[inline]
+pub fn my_proc() -> &'static Proc {
+    unsafe {
+        if MY_PROC_PTR.is_null() {
+            init_process();
+        }
+        &*MY_PROC_PTR
+    }
+}
+
+/// Process any interrupts sent to this thread, unless they are held off.  Long-running code
+/// should call this every so often, so that it can be cancelled (PostgreSQL's
+/// CHECK_FOR_INTERRUPTS).
+#[inline]
+pub fn check_for_interrupts() {
+    MY_PROC.with( |proc_| proc_.check_for_interrupts())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MY_PROC, ProcSignal, check_for_interrupts, my_proc, proc_by_number};
+
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::task;
+
+    #[test]
+    fn test_check_for_interrupts() {
+        assert!(task::try(proc() {
+            check_for_interrupts();
+            MY_PROC.with( |proc_| {
+                proc_.signal(ProcSignal::Terminate);
+                proc_.hold_interrupts();
+                proc_.check_for_interrupts();
+                assert!(proc_.interrupt_pending.load(SeqCst));
+                proc_.resume_interrupts();
+            });
+            check_for_interrupts();
+        }).is_err());
+    }
+
+    #[test]
+    fn test_no_interrupts() {
+        MY_PROC.with( |proc_| {
+            proc_.hold_interrupts();
+            proc_.resume_interrupts();
+            proc_.check_for_interrupts();
+            assert_eq!(proc_.interrupt_holdoff_count.get(), 0);
+        });
+    }
+
+    #[test]
+    fn test_proc_slots() {
+        let pgprocno = my_proc().pgprocno;
+        assert!(proc_by_number(pgprocno) as *const _ == my_proc() as *const _);
+        MY_PROC.with( |proc_| assert_eq!(proc_.pgprocno, pgprocno));
+
+        // Each running thread has a Proc of its own.
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(my_proc().pgprocno);
+        });
+        assert!(rx.recv() != pgprocno);
+    }
+
+    #[test]
+    fn test_wake_by_number() {
+        // Another thread can wake us knowing only our number.
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let proc_ = my_proc();
+            tx.send(proc_.pgprocno);
+            proc_.sem.acquire();
+            tx.send(proc_.pgprocno);
+        });
+        let other = rx.recv();
+        other.get().sem.release();
+        assert_eq!(rx.recv(), other);
+    }
+}
@@ -0,0 +1,182 @@
+// The array of running threads' Procs.
+//
+// Every thread adds its `Proc` here when it starts and removes it when it exits (see
+// `process`), so anything that needs to look at all running transactions, such as taking a
+// snapshot, working out the oldest xmin or reporting activity, starts here.  The array is
+// protected by `lwlock::proc_array_lock()`: adding or removing a Proc takes it in exclusive
+// mode, and looking through the array takes it in shared mode.
+
+use lwlock::{mod, LWLockSharedGuard};
+use process::{Proc, ProcNumber, MAX_BACKENDS};
+use trans::TransactionId;
+
+/// The pgprocnos of all registered Procs, kept sorted so that looking through them walks the
+/// Procs in memory order.
+struct ProcArrayStruct {
+    num_procs: uint,
+    pgprocnos: [ProcNumber, ..MAX_BACKENDS],
+}
+
+static mut PROC_ARRAY: ProcArrayStruct = ProcArrayStruct {
+    num_procs: 0,
+    pgprocnos: [ProcNumber(0), ..MAX_BACKENDS],
+};
+
+/// Add a starting thread's Proc to the array.
+#[doc(hidden)]
+pub fn add(proc_: &Proc) {
+    let _guard = lwlock::proc_array_lock().lock_exclusive();
+    unsafe {
+        let array = &mut PROC_ARRAY;
+        // Every Proc has a slot, so there is always room.
+        assert!(array.num_procs < MAX_BACKENDS);
+        let mut index = array.num_procs;
+        while index > 0 && array.pgprocnos[index - 1] > proc_.pgprocno {
+            array.pgprocnos[index] = array.pgprocnos[index - 1];
+            index -= 1;
+        }
+        array.pgprocnos[index] = proc_.pgprocno;
+        array.num_procs += 1;
+    }
+}
+
+/// Remove an exiting thread's Proc from the array.  Its transaction, if any, is over.
+#[doc(hidden)]
+pub fn remove(proc_: &Proc) {
+    let _guard = lwlock::proc_array_lock().lock_exclusive();
+    proc_.set_xid(TransactionId::invalid());
+    proc_.set_xmin(TransactionId::invalid());
+    unsafe {
+        let array = &mut PROC_ARRAY;
+        let num_procs = array.num_procs;
+        match array.pgprocnos.slice_to(num_procs).position_elem(&proc_.pgprocno) {
+            Some(index) => {
+                for i in range(index, num_procs - 1) {
+                    array.pgprocnos[i] = array.pgprocnos[i + 1];
+                }
+                array.num_procs -= 1;
+            },
+            None => panic!("failed to find proc {} in ProcArray", proc_.pgprocno),
+        }
+    }
+}
+
+/// The ProcArray, locked in shared mode so that no thread can start or exit while we look
+/// through it.  Get one with `shared()`.
+pub struct ProcArray {
+    _guard: LWLockSharedGuard<'static, ()>,
+}
+
+/// Lock the ProcArray in shared mode.
+pub fn shared() -> ProcArray {
+    ProcArray { _guard: lwlock::proc_array_lock().lock_shared() }
+}
+
+impl ProcArray {
+    /// The number of running threads.
+    pub fn len(&self) -> uint {
+        unsafe { PROC_ARRAY.num_procs }
+    }
+
+    /// Iterate over the running threads' Procs, in pgprocno order.
+    pub fn iter<'a>(&'a self) -> Procs<'a> {
+        Procs { _array: self, index: 0 }
+    }
+}
+
+pub struct Procs<'a> {
+    _array: &'a ProcArray,
+    index: uint,
+}
+
+impl<'a> Iterator<&'static Proc> for Procs<'a> {
+    fn next(&mut self) -> Option<&'static Proc> {
+        unsafe {
+            if self.index >= PROC_ARRAY.num_procs {
+                return None
+            }
+            let proc_ = PROC_ARRAY.pgprocnos[self.index].get();
+            self.index += 1;
+            Some(proc_)
+        }
+    }
+}
+
+/// The oldest xid that any running thread's transaction or snapshot considers running, or an
+/// invalid ID if none does.  Tuples deleted by transactions before this are dead to everyone.
+pub fn oldest_xmin() -> TransactionId {
+    let array = shared();
+    let mut result = TransactionId::invalid();
+    for proc_ in array.iter() {
+        for xid in [proc_.xid(), proc_.xmin()].iter() {
+            if xid.is_normal() && (!result.is_valid() || xid.precedes(&result)) {
+                result = xid.clone();
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{oldest_xmin, shared};
+    use process::{mod, ProcNumber};
+    use trans::TransactionId;
+
+    use std::task;
+
+    fn registered(pgprocno: ProcNumber) -> bool {
+        shared().iter().any( |proc_| proc_.pgprocno == pgprocno)
+    }
+
+    #[test]
+    fn test_register() {
+        let pgprocno = process::my_proc().pgprocno;
+        {
+            let array = shared();
+            assert!(array.len() >= 1);
+            let pgprocnos: Vec<ProcNumber> = array.iter().map( |proc_| proc_.pgprocno).collect();
+            assert!(pgprocnos.windows(2).all( |w| w[0] < w[1]));
+        }
+        assert!(registered(pgprocno));
+
+        let xid = TransactionId::from_u32(1234);
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel();
+        let xid_ = xid.clone();
+        spawn(proc() {
+            let proc_ = process::my_proc();
+            proc_.set_xid(xid_);
+            tx.send(proc_.pgprocno);
+            done_rx.recv();
+        });
+        let other = rx.recv();
+        let running = || {
+            shared().iter().any( |proc_| proc_.pgprocno == other && proc_.xid() == xid)
+        };
+        assert!(running());
+        done_tx.send(());
+        // The thread removes itself on the way out, ending its transaction.  Its number may
+        // belong to another thread again by the time we look, but that thread's Proc starts out
+        // with no xid.
+        while running() {
+            task::deschedule();
+        }
+    }
+
+    #[test]
+    fn test_oldest_xmin() {
+        let (tx, rx) = channel();
+        let (done_tx, done_rx) = channel();
+        spawn(proc() {
+            let proc_ = process::my_proc();
+            proc_.set_xid(TransactionId::from_u32(3));
+            tx.send(());
+            done_rx.recv();
+        });
+        rx.recv();
+        // Nothing running can be older than the first normal xid.
+        assert_eq!(oldest_xmin(), TransactionId::from_u32(3));
+        done_tx.send(());
+    }
+}
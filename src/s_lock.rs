@@ -1,171 +1,614 @@
 // Hardware-dependent spin locks.
 #![macro_escape]
 
+use lockdep::{mod, LockKind};
+
 use std::cmp;
 use std::cell::UnsafeCell;
-use std::io::{IoResult, Timer};
+use std::fmt;
+use std::io::{IoError, Timer};
+#[cfg(feature = "spinlock-profile")]
+use std::io::IoResult;
 use std::kinds::marker;
-use std::rand::{mod, Closed01, Rng};
+use std::rand::{mod, Closed01, Rng, TaskRng};
 use std::rt;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, SeqCst};
 use std::time::Duration;
+use time;
 
-#[cfg(target_arch = "x86_64")]
+// A single byte on every target.  Where the hardware has no byte-sized atomic operations,
+// the compiler emulates them with word-sized ones.
 #[doc(hidden)]
 pub type SLock = u8;
 
+/// A spinlock protecting `before` and `after`.
+///
+/// `K` decides which waiter gets the lock next.  The default, `TasLock`, is a single
+/// test-and-set byte: cheap, but every waiter spins on the same cache line, and it is a free
+/// for all whenever the lock is released.  `TicketLock` serves waiters in the order they
+/// arrived, and holds up much better when many threads want the lock at once.
 #[repr(C)]
-pub struct SpinLock<T, U> {
+pub struct SpinLock<T, U, K = TasLock> {
     pub before: UnsafeCell<T>,
-    #[doc(hidden)] pub lock: UnsafeCell<SLock>,
+    #[doc(hidden)] pub lock: K,
+    #[doc(hidden)] pub lockdep_id: UnsafeCell<u32>, // Assigned by lockdep when first used
     pub after: UnsafeCell<U>,
     #[doc(hidden)] pub nocopy: marker::NoCopy
 }
 
-const DEFAULT_SPINS_PER_DELAY: u32 = 100;
-#[thread_local] static mut SPINS_PER_DELAY: u32 = DEFAULT_SPINS_PER_DELAY;
+/// How a `SpinLock` arbitrates between the threads that want it.
+pub trait SpinLockKind {
+    /// An unlocked lock.
+    fn new() -> Self;
+
+    /// Take the lock, giving up once `config`'s limits are exceeded.  Returns the number of
+    /// times we had to sleep.
+    fn lock(&self, config: &SpinLockConfig,
+            file_line: &(&'static str, uint)) -> Result<u32, SpinLockError>;
+
+    /// Release the lock, which the caller must hold.
+    fn unlock(&self);
+
+    /// Whether the lock is currently free.
+    fn free(&self) -> bool;
+}
+
+/// The default `SpinLock` kind: a test-and-set byte.
+pub struct TasLock {
+    #[doc(hidden)] pub slock: UnsafeCell<SLock>,
+}
+
+/// A first-come, first-served `SpinLock` kind.  Each waiter takes a ticket and spins until the
+/// lock is serving it, backing off in proportion to how far back in line it is.
+///
+/// A waiter that decides the lock is stuck leaves its ticket behind as abandoned, and the lock
+/// is passed straight on past it when its turn comes, so everyone behind it still gets the
+/// lock.  Only `MAX_ABANDONED_TICKETS` tickets can be left at once; a waiter that finds no room
+/// waits for its turn and passes the lock on itself before returning the error.
+pub struct TicketLock {
+    #[doc(hidden)] pub next_ticket: AtomicUint,
+    #[doc(hidden)] pub now_serving: AtomicUint,
+    /// # of tickets in abandoned_tickets
+    #[doc(hidden)] pub num_abandoned: AtomicUint,
+    /// tickets given up by their waiters, plus one; zero if the slot is free
+    #[doc(hidden)] pub abandoned_tickets: [AtomicUint, ..MAX_ABANDONED_TICKETS],
+}
+
+/// How many waiters can have left a `TicketLock` ticket behind at once.
+pub const MAX_ABANDONED_TICKETS: uint = 4;
+
+/// How long to keep trying for a spinlock before deciding it is stuck.
+///
+/// After spinning for a while, a waiter sleeps for `min_delay_usec` microseconds, and each
+/// further sleep is longer by a random fraction between 1X and 2X, wrapping back to
+/// `min_delay_usec` once it exceeds `max_delay_usec`.  The lock is considered stuck after
+/// `num_delays` sleeps.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct SpinLockConfig {
+    pub num_delays: u32,
+    pub min_delay_usec: u32,
+    pub max_delay_usec: u32,
+}
+
+/// The limits used by `acquire`, `acquire_guard` and `spin_lock_acquire!`: two minutes or so
+/// of trying, usually.
+pub const DEFAULT_SPIN_LOCK_CONFIG: SpinLockConfig = SpinLockConfig {
+    num_delays: 1000,
+    min_delay_usec: 1000,
+    max_delay_usec: 1000000,
+};
+
+/// Why we failed to acquire a spinlock.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum SpinLockError {
+    /// We gave up waiting for the lock.
+    Stuck(StuckSpinLock),
+    /// We couldn't get a timer to sleep on between spins.
+    Io(IoError),
+}
+
+/// A spinlock we gave up on.
+#[deriving(Clone, PartialEq, Eq)]
+pub struct StuckSpinLock {
+    /// Where we were trying to acquire it.
+    pub file_line: (&'static str, uint),
+    /// How long we tried for, from when we first had to sleep after the lock last changed hands.
+    pub waited: Duration,
+}
+
+impl fmt::Show for StuckSpinLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stuck spinlock detected at {}:{} after {}",
+               self.file_line.0, self.file_line.1, self.waited)
+    }
+}
+
+/// Contention at one call site that acquires spinlocks, as gathered with the
+/// `spinlock-profile` feature.  Only acquisitions that had to spin are counted.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct SpinLockProfile {
+    /// The call site, as passed to `acquire`.
+    pub file_line: (&'static str, uint),
+    /// # of acquisitions that found the lock taken
+    pub contended: u64,
+    /// # of times we spun
+    pub spins: u64,
+    /// # of times we slept
+    pub delays: u64,
+    /// total time spent sleeping
+    pub delay_time_ns: u64,
+}
+
+/// The `n` call sites that have waited longest for spinlocks, worst first.  Sites that never
+/// had to sleep are ranked by how much they spun.
+#[cfg(feature = "spinlock-profile")]
+pub fn worst_spin_lock_sites(n: uint) -> Vec<SpinLockProfile> {
+    let mut sites = profile::sites();
+    sites.sort_by( |a, b| (b.delay_time_ns, b.spins).cmp(&(a.delay_time_ns, a.spins)));
+    sites.truncate(n);
+    sites
+}
+
+/// Write the `n` worst call sites (see `worst_spin_lock_sites`) to `w`, one per line.
+#[cfg(feature = "spinlock-profile")]
+pub fn dump_spin_lock_profile<W>(w: &mut W, n: uint) -> IoResult<()> where W: Writer {
+    for site in worst_spin_lock_sites(n).iter() {
+        try!(writeln!(w, "{}:{}: contended {}, spins {}, delays {}, delay time {}us",
+                      site.file_line.0, site.file_line.1, site.contended, site.spins,
+                      site.delays, site.delay_time_ns / 1000));
+    }
+    Ok(())
+}
+
+/// Forget everything profiled so far.
+#[cfg(feature = "spinlock-profile")]
+pub fn reset_spin_lock_profile() {
+    profile::reset()
+}
 
-pub fn set_spins_per_delay(shared_spins_per_delay: u32) {
+#[cfg(feature = "spinlock-profile")]
+mod profile {
+    use super::SpinLockProfile;
+
+    use std::collections::HashMap;
+    use std::mem;
+    use std::sync::{StaticMutex, MUTEX_INIT};
+    use time;
+
+    // Guards PROFILE.  This can't be a SpinLock, since those are profiled too.
+    static PROFILE_LOCK: StaticMutex = MUTEX_INIT;
+    // Allocated on first use, and never freed.
+    static mut PROFILE: *mut HashMap<(&'static str, uint), SpinLockProfile> =
+        0 as *mut HashMap<(&'static str, uint), SpinLockProfile>;
+
+    #[inline]
+    pub fn now() -> u64 {
+        time::precise_time_ns()
+    }
+
+    fn with_profile<R>(f: |&mut HashMap<(&'static str, uint), SpinLockProfile>| -> R) -> R {
+        let _guard = PROFILE_LOCK.lock();
+        let profile = unsafe {
+            if PROFILE.is_null() {
+                let profile: Box<HashMap<(&'static str, uint), SpinLockProfile>> =
+                    box HashMap::new();
+                PROFILE = mem::transmute(profile);
+            }
+            &mut *PROFILE
+        };
+        f(profile)
+    }
+
+    // Contention this thread has seen but not yet added to PROFILE.  Taking PROFILE_LOCK while
+    // holding a spinlock would stretch out the critical sections of exactly the locks that are
+    // most contended, so we wait until the thread has released every spinlock it holds.
+    const MAX_PENDING: uint = 16;
+    #[thread_local]
+    static mut PENDING: [((&'static str, uint), u64, u64, u64), ..MAX_PENDING] =
+        [(("", 0), 0, 0, 0), ..MAX_PENDING];
+    #[thread_local]
+    static mut NUM_PENDING: uint = 0;
+    // # of spinlocks this thread holds
+    #[thread_local]
+    static mut NUM_HELD: uint = 0;
+
+    /// Note contention at `file_line`, to be recorded once this thread holds no spinlocks.
+    pub fn record(file_line: &(&'static str, uint), spins: u64, delays: u64,
+                  delay_time_ns: u64) {
+        unsafe {
+            if NUM_PENDING == MAX_PENDING {
+                // Holding this many spinlocks at once is unheard of, so don't bother waiting.
+                flush();
+            }
+            PENDING[NUM_PENDING] = (*file_line, spins, delays, delay_time_ns);
+            NUM_PENDING += 1;
+        }
+    }
+
+    #[inline]
+    pub fn acquired() {
+        unsafe { NUM_HELD += 1; }
+    }
+
+    #[inline]
+    pub fn released() {
+        unsafe {
+            if NUM_HELD > 0 {
+                NUM_HELD -= 1;
+            }
+            if NUM_HELD == 0 && NUM_PENDING > 0 {
+                flush();
+            }
+        }
+    }
+
+    #[cold] #[inline(never)]
+    fn flush() {
+        with_profile( |profile| {
+            for &(file_line, spins, delays, delay_time_ns) in
+                    unsafe { PENDING.slice_to(NUM_PENDING).iter() } {
+                if !profile.contains_key(&file_line) {
+                    profile.insert(file_line, SpinLockProfile {
+                        file_line: file_line,
+                        contended: 0,
+                        spins: 0,
+                        delays: 0,
+                        delay_time_ns: 0,
+                    });
+                }
+                let site = profile.get_mut(&file_line).unwrap();
+                site.contended += 1;
+                site.spins += spins;
+                site.delays += delays;
+                site.delay_time_ns += delay_time_ns;
+            }
+        });
+        unsafe { NUM_PENDING = 0; }
+    }
+
+    pub fn sites() -> Vec<SpinLockProfile> {
+        with_profile( |profile| profile.values().map( |site| site.clone()).collect())
+    }
+
+    pub fn reset() {
+        with_profile( |profile| profile.clear())
+    }
+}
+
+#[cfg(not(feature = "spinlock-profile"))]
+mod profile {
+    #[inline(always)]
+    pub fn now() -> u64 {
+        0
+    }
+
+    #[inline(always)]
+    pub fn record(_file_line: &(&'static str, uint), _spins: u64, _delays: u64,
+                  _delay_time_ns: u64) {}
+
+    #[inline(always)]
+    pub fn acquired() {}
+
+    #[inline(always)]
+    pub fn released() {}
+}
+
+pub const DEFAULT_SPINS_PER_DELAY: u32 = 100;
+
+/// The spinlock delay estimate shared by all threads.  Each thread starts from it the first
+/// time it has to spin, and folds its own estimate back in when it exits, so that short-lived
+/// threads don't have to learn how contended the process's spinlocks are from scratch.  Zero
+/// until some thread has folded its estimate in.
+static SHARED_SPINS_PER_DELAY: AtomicUint = INIT_ATOMIC_UINT;
+
+/// This thread's estimate, or zero if it hasn't had to spin yet.
+#[thread_local] static mut SPINS_PER_DELAY: u32 = 0;
+
+/// The process-wide estimate of how many times to spin before sleeping.
+pub fn shared_spins_per_delay() -> u32 {
+    shared_estimate(&SHARED_SPINS_PER_DELAY)
+}
+
+/// The estimate a thread starts from, given a shared one like `SHARED_SPINS_PER_DELAY`.
+fn shared_estimate(shared: &AtomicUint) -> u32 {
+    estimate(shared.load(SeqCst))
+}
+
+/// A shared estimate's value, which is zero until some thread has folded its estimate in.
+fn estimate(shared: uint) -> u32 {
+    match shared {
+        0 => DEFAULT_SPINS_PER_DELAY,
+        spins_per_delay => spins_per_delay as u32,
+    }
+}
+
+/// Fold an exiting thread's estimate into a shared one like `SHARED_SPINS_PER_DELAY`.
+fn fold_into_shared(shared: &AtomicUint, spins_per_delay: u32) {
+    loop {
+        let old = shared.load(SeqCst);
+        let new = fold_spins_per_delay(estimate(old), spins_per_delay) as uint;
+        if shared.compare_and_swap(old, new, SeqCst) == old {
+            break
+        }
+    }
+}
+
+/// This thread's current estimate of how many times to spin before sleeping.
+pub fn spins_per_delay() -> u32 {
     unsafe {
-        SPINS_PER_DELAY = shared_spins_per_delay;
+        if SPINS_PER_DELAY == 0 {
+            set_spins_per_delay(shared_spins_per_delay());
+        }
+        SPINS_PER_DELAY
     }
 }
 
-pub fn update_spins_per_delay(shared_spins_per_delay: u32) -> u32 {
+/// Replace this thread's estimate.  It is folded into the process-wide one when the thread
+/// exits, like one the thread learned itself.
+pub fn set_spins_per_delay(spins_per_delay: u32) {
     unsafe {
-        let spins_per_delay = SPINS_PER_DELAY;
-        (shared_spins_per_delay * 15 + spins_per_delay) / 16
+        SPINS_PER_DELAY = spins_per_delay;
     }
+    // Arrange to pass on what we learn to threads started after us.
+    SPINS_PER_DELAY_EXIT.with( |_| ());
 }
 
-impl<T, U> SpinLock<T, U> where T: Send, U: Send {
-    #[cfg(target_arch = "x86_64")]
+/// Fold this thread's estimate into the process-wide one, returning the new process-wide
+/// value.  A single thread only moves it a little.
+pub fn update_spins_per_delay(shared_spins_per_delay: u32) -> u32 {
+    unsafe { fold_spins_per_delay(shared_spins_per_delay, SPINS_PER_DELAY) }
+}
+
+fn fold_spins_per_delay(shared_spins_per_delay: u32, spins_per_delay: u32) -> u32 {
+    (shared_spins_per_delay * 15 + spins_per_delay) / 16
+}
+
+/// Folds this thread's estimate into the process-wide one when the thread exits.
+struct SpinsPerDelayExit;
+
+impl Drop for SpinsPerDelayExit {
+    fn drop(&mut self) {
+        unsafe { fold_into_shared(&SHARED_SPINS_PER_DELAY, SPINS_PER_DELAY) }
+    }
+}
+
+thread_local!(static SPINS_PER_DELAY_EXIT: SpinsPerDelayExit = SpinsPerDelayExit)
+
+// x86 and x86_64
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl TasLock {
     #[inline(always)]
     fn tas(&self) -> bool {
         unsafe {
-            ::std::intrinsics::atomic_xchg(self.lock.get(), 1) != 0
+            ::std::intrinsics::atomic_xchg(self.slock.get(), 1) != 0
         }
     }
 
-    #[cfg(target_arch = "x86_64")]
+    // On x86 a plain load is enough to see whether the lock is worth trying for, and doesn't
+    // tie up the cache line the way a locked xchg does.
     #[inline(always)]
     fn tas_spin(&self) -> bool {
         unsafe {
-            *self.lock.get() != 0 || self.tas()
+            *self.slock.get() != 0 || self.tas()
         }
     }
+}
 
-    #[cfg(target_arch = "x86_64")]
+// Adding a PAUSE in the spin delay loop is demonstrably a no-op on Opteron, but it may be of
+// some help on EM64T, and it is reported to help on Xeons with hyperthreading.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[inline(always)]
+fn spin_delay() {
+    unsafe {
+        asm!("rep; nop")
+    }
+}
+
+// aarch64: ISB stalls the pipeline for long enough to be a useful delay, which YIELD doesn't
+// on most implementations.
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+fn spin_delay() {
+    unsafe {
+        asm!("isb" ::: "memory" : "volatile")
+    }
+}
+
+// Default definitions -- override these as needed
+
+// Acquire ordering is all taking the lock needs; unlock releases.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+impl TasLock {
     #[inline(always)]
-    fn spin_delay() {
+    fn tas(&self) -> bool {
         unsafe {
-            asm!("rep; nop")
+            ::std::intrinsics::atomic_xchg_acq(self.slock.get(), 1) != 0
         }
     }
 
-    // Default definitions -- override these as needed
     #[inline(always)]
-    fn lock_(&self, file_line: &(&'static str, uint)) -> IoResult<u32> {
-        if self.tas() {
-            self.lock(file_line)
-        } else {
-            Ok(0)
+    fn tas_spin(&self) -> bool {
+        unsafe {
+            ::std::intrinsics::atomic_load_relaxed(self.slock.get() as *const SLock) != 0 ||
+                self.tas()
         }
     }
+}
 
-    #[inline(always)]
-    fn free_(&self) -> bool {
-        unsafe {
-            ::std::intrinsics::atomic_load_acq(self.lock.get() as *const SLock) == 0
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+#[inline(always)]
+fn spin_delay() { }
+
+// Platform independent definitions
+#[cold] #[inline(never)]
+fn lock_stuck(err: SpinLockError, file_line: &(&'static str, uint)) -> ! {
+    match err {
+        SpinLockError::Stuck(stuck) => rt::begin_unwind(stuck.to_string(), file_line),
+        SpinLockError::Io(err) => rt::begin_unwind(err.to_string(), file_line),
+    }
+}
+
+/// The sleeps between bouts of spinning, while waiting for a spinlock.  Also counts the
+/// spins and sleeps for the call site's profile.
+struct Backoff<'a> {
+    file_line: &'a (&'static str, uint),
+    /// spins, in total
+    spins: u64,
+    /// sleeps, in total
+    delays: u32,
+    /// sleeps since we last saw progress
+    stalled_delays: u32,
+    cur_delay: u32,
+    /// when we first slept since the lock last changed hands, or 0
+    first_delay_ns: u64,
+    /// time spent sleeping, if profiling
+    delay_time_ns: u64,
+    rng: Option<TaskRng>,
+    timer: Option<Timer>,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(file_line: &'a (&'static str, uint)) -> Backoff<'a> {
+        Backoff {
+            file_line: file_line,
+            spins: 0,
+            delays: 0,
+            stalled_delays: 0,
+            cur_delay: 0,
+            first_delay_ns: 0,
+            delay_time_ns: 0,
+            rng: None,
+            timer: None,
         }
     }
 
-    #[inline(always)]
-    fn unlock_(&self) {
-        unsafe {
-            ::std::intrinsics::atomic_store_rel(self.lock.get(), 0)
+    /// The lock changed hands, so it isn't stuck: start over from the shortest sleep, and
+    /// only count the time spent waiting from here on.
+    fn restart(&mut self) {
+        self.stalled_delays = 0;
+        self.cur_delay = 0;
+        self.first_delay_ns = 0;
+    }
+
+    /// Sleep, for longer than last time, or report the lock stuck if we have slept too often.
+    fn delay(&mut self, config: &SpinLockConfig) -> Result<(), SpinLockError> {
+        // Start the clock before anything else, so that we can say how long we waited even if
+        // the limit is no sleeps at all.
+        if self.first_delay_ns == 0 { // first time to delay since the lock changed hands?
+            self.first_delay_ns = time::precise_time_ns();
+        }
+        if self.cur_delay == 0 {
+            self.cur_delay = config.min_delay_usec;
         }
+
+        self.delays += 1;
+        self.stalled_delays += 1;
+        if self.stalled_delays > config.num_delays {
+            let waited = time::precise_time_ns() - self.first_delay_ns;
+            return Err(SpinLockError::Stuck(StuckSpinLock {
+                file_line: *self.file_line,
+                waited: Duration::nanoseconds(waited as i64),
+            }))
+        }
+
+        let duration = Duration::microseconds(self.cur_delay as i64);
+        let start = profile::now();
+        match self.timer {
+            None => {
+                let mut timer = match Timer::new() {
+                    Ok(timer) => timer,
+                    Err(err) => return Err(SpinLockError::Io(err)),
+                };
+                timer.sleep(duration);
+                self.timer = Some(timer);
+            }
+            Some(ref mut timer) => timer.sleep(duration),
+        };
+        self.delay_time_ns += profile::now() - start;
+        // increase delay by a random fraction between 1X and 2X
+        self.cur_delay += (self.cur_delay as f64 * match self.rng {
+            None => {
+                let mut rng = rand::task_rng();
+                let frac = rng.gen::<Closed01<f64>>().0;
+                self.rng = Some(rng);
+                frac
+            },
+            Some(ref mut rng) => rng.gen::<Closed01<f64>>().0,
+        }) as u32;
+        // wrap back to minimum delay when maximum is exceeded
+        if self.cur_delay > config.max_delay_usec {
+            self.cur_delay = config.min_delay_usec;
+        }
+        Ok(())
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Backoff<'a> {
+    fn drop(&mut self) {
+        // We only get here if the lock was contended, whether or not we got it in the end.  If
+        // we did, this is only noted for now, and recorded once we let go of the lock.
+        profile::record(self.file_line, self.spins, self.delays as u64, self.delay_time_ns);
     }
+}
 
+impl SpinLockKind for TasLock {
     #[inline(always)]
-    fn init_(before: T, after: U) -> SpinLock<T, U> {
-        SpinLock {
-            before: UnsafeCell::new(before),
-            lock: UnsafeCell::new(0),
-            after: UnsafeCell::new(after),
-            nocopy: marker::NoCopy,
+    fn new() -> TasLock {
+        TasLock { slock: UnsafeCell::new(0) }
+    }
+
+    #[inline(always)]
+    fn lock(&self, config: &SpinLockConfig,
+            file_line: &(&'static str, uint)) -> Result<u32, SpinLockError> {
+        if self.tas() {
+            self.lock_slow(config, file_line)
+        } else {
+            Ok(0)
         }
     }
 
-    #[cfg(not(target_arch = "x86_64"))]
     #[inline(always)]
-    fn spin_delay() { }
+    fn unlock(&self) {
+        unsafe {
+            ::std::intrinsics::atomic_store_rel(self.slock.get(), 0)
+        }
+    }
 
-    // Platform independent definitions
-    #[cold] #[inline(never)]
-    fn lock_stuck(file_line: &(&'static str, uint)) {
-        rt::begin_unwind("Stuck spinlock detected", file_line);
+    #[inline(always)]
+    fn free(&self) -> bool {
+        unsafe {
+            ::std::intrinsics::atomic_load_acq(self.slock.get() as *const SLock) == 0
+        }
     }
+}
 
-    fn lock(&self, file_line: &(&'static str, uint)) -> IoResult<u32> {
+impl TasLock {
+    fn lock_slow(&self, config: &SpinLockConfig,
+                 file_line: &(&'static str, uint)) -> Result<u32, SpinLockError> {
         const MIN_SPINS_PER_DELAY: u32 = 10;
         const MAX_SPINS_PER_DELAY: u32 = 1000;
-        const NUM_DELAYS: u32 = 1000;
-        const MIN_DELAY_USEC: u32 = 1000;
-        const MAX_DELAY_USEC: u32 = 1000000;
         {
             let mut spins = 0;
-            let mut delays = 0;
-            let mut cur_delay = 0;
-            let mut rng = None;
-            let mut timer = None;
+            let mut backoff = Backoff::new(file_line);
 
-            let spins_per_delay = unsafe { SPINS_PER_DELAY };
+            let spins_per_delay = spins_per_delay();
 
             while self.tas_spin() {
                 // CPU-specific delay each time through the loop
-                SpinLock::<T,U>::spin_delay();
+                spin_delay();
 
                 // Block the process every spins_per_delay tries
                 spins += 1;
+                backoff.spins += 1;
                 if spins >= spins_per_delay {
-                    delays += 1;
-                    if delays > NUM_DELAYS {
-                        SpinLock::<(),()>::lock_stuck(file_line);
-                    }
-
-                    if cur_delay == 0 { // first time to delay?
-                        cur_delay = MIN_DELAY_USEC;
-                    }
-
-                    let duration = Duration::microseconds(cur_delay as i64);
-                    match timer {
-                        None => {
-                            let mut timer_ = try!(Timer::new());
-                            timer_.sleep(duration);
-                            timer = Some(timer_);
-                        }
-                        Some(ref mut timer) => timer.sleep(duration),
-                    };
-                    // increase delay by a random fraction between 1X and 2X
-                    cur_delay += (cur_delay as f64 * match rng {
-                        None => {
-                            let mut rng_ = rand::task_rng();
-                            let frac = rng_.gen::<Closed01<f64>>().0;
-                            rng = Some(rng_);
-                            frac
-                        },
-                        Some(ref mut rng) => rng.gen::<Closed01<f64>>().0,
-                    }) as u32;
-                    // wrap back to minimum delay when maximum is exceeded
-                    if cur_delay > MAX_DELAY_USEC {
-                        cur_delay = MIN_DELAY_USEC;
-                    }
-
+                    try!(backoff.delay(config));
                     spins = 0;
                 }
             }
 
-            if cur_delay == 0 {
+            if backoff.delays == 0 {
                 // we never had to delay
                 if spins_per_delay < MAX_SPINS_PER_DELAY {
                     unsafe {
@@ -179,46 +622,257 @@ impl<T, U> SpinLock<T, U> where T: Send, U: Send {
                     }
                 }
             }
-            Ok(delays)
+            Ok(backoff.delays)
         }
     }
+}
 
-    // Public interface
+impl SpinLockKind for TicketLock {
+    #[inline(always)]
+    fn new() -> TicketLock {
+        TicketLock {
+            next_ticket: AtomicUint::new(0),
+            now_serving: AtomicUint::new(0),
+            num_abandoned: AtomicUint::new(0),
+            abandoned_tickets: [AtomicUint::new(0), AtomicUint::new(0),
+                                AtomicUint::new(0), AtomicUint::new(0)],
+        }
+    }
+
+    #[inline(always)]
+    fn lock(&self, config: &SpinLockConfig,
+            file_line: &(&'static str, uint)) -> Result<u32, SpinLockError> {
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        let serving = self.now_serving.load(Acquire);
+        if serving == ticket {
+            Ok(0)
+        } else {
+            self.lock_slow(ticket, serving, config, file_line)
+        }
+    }
+
+    #[inline(always)]
+    fn unlock(&self) {
+        // Only the holder moves now_serving, so this needn't be atomic.  The store has to be
+        // ordered before we look for abandoned tickets, though (see `abandon`).
+        let serving = self.now_serving.load(Relaxed);
+        self.now_serving.store(serving + 1, SeqCst);
+        if self.num_abandoned.load(SeqCst) != 0 {
+            self.skip_abandoned(serving + 1);
+        }
+    }
+
+    #[inline(always)]
+    fn free(&self) -> bool {
+        let serving = self.now_serving.load(Acquire);
+        self.next_ticket.load(Acquire) == serving
+    }
+}
+
+impl TicketLock {
+    fn lock_slow(&self, ticket: uint, mut serving: uint, config: &SpinLockConfig,
+                 file_line: &(&'static str, uint)) -> Result<u32, SpinLockError> {
+        let mut spins = 0;
+        let mut backoff = Backoff::new(file_line);
+
+        let spins_per_delay = spins_per_delay();
+
+        loop {
+            // Everyone ahead of us will hold the lock first, so don't look again any sooner
+            // than they could all be done with it.
+            for _ in range(0, ticket - serving) {
+                spin_delay();
+            }
+
+            let now_serving = self.now_serving.load(Acquire);
+            if now_serving == ticket {
+                break
+            }
+            if now_serving != serving {
+                // The line is moving, so whoever held the lock before wasn't stuck.  Only
+                // sleep if the current holder hangs on to it for a long time.
+                serving = now_serving;
+                backoff.restart();
+                spins = 0;
+                continue
+            }
+
+            spins += 1;
+            backoff.spins += 1;
+            if spins >= spins_per_delay {
+                match backoff.delay(config) {
+                    Ok(()) => {},
+                    Err(err) => {
+                        self.abandon(ticket);
+                        return Err(err)
+                    },
+                }
+                spins = 0;
+            }
+        }
+        Ok(backoff.delays)
+    }
+
+    /// Give up our place in line.  Whoever releases the lock when `ticket`'s turn comes
+    /// passes it straight on to the ticket after.
+    #[cold] #[inline(never)]
+    fn abandon(&self, ticket: uint) {
+        // Count the ticket before leaving it, so that a releaser that finds no abandoned
+        // tickets is sure to have moved now_serving before we look at it below.
+        self.num_abandoned.fetch_add(1, SeqCst);
+        let left = self.abandoned_tickets.iter()
+            .any( |slot| slot.compare_and_swap(0, ticket + 1, SeqCst) == 0);
+        if !left {
+            // Nowhere to leave it, so wait for our turn and pass the lock on ourselves.
+            self.num_abandoned.fetch_sub(1, SeqCst);
+            while self.now_serving.load(Acquire) != ticket {
+                spin_delay();
+            }
+            self.unlock();
+            return
+        }
+        // If our turn came while we were leaving the ticket, the releaser may have looked
+        // before it was there.  Then pass the lock on ourselves, unless the releaser saw the
+        // ticket after all and took it first.
+        if self.now_serving.load(SeqCst) == ticket && self.take_abandoned(ticket) {
+            self.unlock();
+        }
+    }
+
+    /// Take `ticket` out of the abandoned tickets, if it's there.  Whoever takes it owes the
+    /// lock to the next ticket.
+    fn take_abandoned(&self, ticket: uint) -> bool {
+        for slot in self.abandoned_tickets.iter() {
+            if slot.compare_and_swap(ticket + 1, 0, SeqCst) == ticket + 1 {
+                self.num_abandoned.fetch_sub(1, SeqCst);
+                return true
+            }
+        }
+        false
+    }
+
+    /// Pass the lock, which is now serving `serving`, on past any abandoned tickets.
+    #[cold] #[inline(never)]
+    fn skip_abandoned(&self, mut serving: uint) {
+        while self.take_abandoned(serving) {
+            serving += 1;
+            self.now_serving.store(serving, SeqCst);
+        }
+    }
+}
+
+impl<T, U> SpinLock<T, U> where T: Send, U: Send {
     #[inline(always)]
     pub fn init(before: T, after: U) -> SpinLock<T, U> {
         SpinLock::init_(before, after)
     }
+}
 
+impl<T, U> SpinLock<T, U, TicketLock> where T: Send, U: Send {
+    /// Like `init`, but waiters get the lock in the order they asked for it.
     #[inline(always)]
-    pub fn acquire(&self, file_line: &(&'static str, uint)) -> IoResult<u32> {
-        self.lock_(file_line)
+    pub fn init_ticket(before: T, after: U) -> SpinLock<T, U, TicketLock> {
+        SpinLock::init_(before, after)
+    }
+}
+
+impl<T, U, K> SpinLock<T, U, K> where T: Send, U: Send, K: SpinLockKind {
+    #[inline(always)]
+    fn init_(before: T, after: U) -> SpinLock<T, U, K> {
+        SpinLock {
+            before: UnsafeCell::new(before),
+            lock: SpinLockKind::new(),
+            lockdep_id: UnsafeCell::new(0),
+            after: UnsafeCell::new(after),
+            nocopy: marker::NoCopy,
+        }
+    }
+
+    // Public interface
+
+    #[inline(always)]
+    pub fn acquire(&self, file_line: &(&'static str, uint)) -> Result<u32, SpinLockError> {
+        self.acquire_with(&DEFAULT_SPIN_LOCK_CONFIG, file_line)
+    }
+
+    /// Like `acquire`, but with the given limits on how long to wait.
+    #[inline(always)]
+    pub fn acquire_with(&self, config: &SpinLockConfig,
+                        file_line: &(&'static str, uint)) -> Result<u32, SpinLockError> {
+        lockdep::check(LockKind::SpinLock, &self.lockdep_id, true, file_line);
+        let delays = try!(self.lock.lock(config, file_line));
+        profile::acquired();
+        lockdep::acquired(LockKind::SpinLock, &self.lockdep_id, true, file_line);
+        Ok(delays)
     }
 
     #[inline(always)]
     pub unsafe fn release(&self) {
-        self.unlock_()
+        lockdep::released(LockKind::SpinLock, &self.lockdep_id);
+        self.lock.unlock();
+        // Now that nobody is waiting on us, record any contention we saw getting the lock.
+        profile::released();
     }
 
     #[inline(always)]
     pub fn free(&self) -> bool {
-        self.free_()
+        self.lock.free()
     }
 
     #[inline(always)]
     pub fn acquire_guard<'a>(&'a self,
-                             file_line: &(&'static str, uint)) -> SpinLockGuard<'a, T, U> {
-        self.lock(file_line).unwrap();
-        SpinLockGuard { lock: self }
+                             file_line: &(&'static str, uint)) -> SpinLockGuard<'a, T, U, K> {
+        self.acquire_guard_counting(file_line).0
+    }
+
+    /// Like `acquire_guard`, but also returns the number of times we had to delay while
+    /// waiting for the lock.
+    #[inline(always)]
+    pub fn acquire_guard_counting<'a>(&'a self, file_line: &(&'static str, uint))
+                                      -> (SpinLockGuard<'a, T, U, K>, u32) {
+        match self.acquire_guard_with(&DEFAULT_SPIN_LOCK_CONFIG, file_line) {
+            Ok(result) => result,
+            Err(err) => lock_stuck(err, file_line),
+        }
+    }
+
+    /// Like `acquire_guard_counting`, but with the given limits on how long to wait, and
+    /// returning an error rather than panicking if the lock looks stuck.
+    #[inline(always)]
+    pub fn acquire_guard_with<'a>(&'a self, config: &SpinLockConfig,
+                                  file_line: &(&'static str, uint))
+                                  -> Result<(SpinLockGuard<'a, T, U, K>, u32), SpinLockError> {
+        lockdep::check(LockKind::SpinLock, &self.lockdep_id, true, file_line);
+        let delays = try!(self.lock.lock(config, file_line));
+        profile::acquired();
+        lockdep::acquired(LockKind::SpinLock, &self.lockdep_id, true, file_line);
+        Ok((SpinLockGuard { lock: self }, delays))
+    }
+
+    /// Like `acquire_guard_counting`, but hidden from the lock-order validator.  This is for
+    /// spinlocks that only protect the internals of some other lock, and that are never held
+    /// while acquiring anything else.
+    #[doc(hidden)]
+    #[inline(always)]
+    pub fn acquire_guard_untracked<'a>(&'a self, file_line: &(&'static str, uint))
+                                       -> (SpinLockGuard<'a, T, U, K>, u32) {
+        match self.lock.lock(&DEFAULT_SPIN_LOCK_CONFIG, file_line) {
+            Ok(delays) => {
+                profile::acquired();
+                (SpinLockGuard { lock: self }, delays)
+            },
+            Err(err) => lock_stuck(err, file_line),
+        }
     }
 
 }
 
 #[must_use]
-pub struct SpinLockGuard<'a, T: 'a, U: 'a> {
-    lock: &'a SpinLock<T, U>,
+pub struct SpinLockGuard<'a, T: 'a, U: 'a, K: 'a = TasLock> {
+    lock: &'a SpinLock<T, U, K>,
 }
 
-impl<'a, T, U> SpinLockGuard<'a, T, U> {
+impl<'a, T, U, K> SpinLockGuard<'a, T, U, K> {
     #[inline(always)]
     pub fn deref<'b>(&'b self) -> (&'a T, &'a U) {
         unsafe {
@@ -235,7 +889,7 @@ impl<'a, T, U> SpinLockGuard<'a, T, U> {
 }
 
 #[unsafe_destructor]
-impl<'a, T, U> Drop for SpinLockGuard<'a, T, U> where T: Send, U: Send {
+impl<'a, T, U, K> Drop for SpinLockGuard<'a, T, U, K> where T: Send, U: Send, K: SpinLockKind {
     #[inline(always)]
     fn drop(&mut self) {
         unsafe {
@@ -248,7 +902,29 @@ macro_rules! spin_lock_init(
     ($before:expr, $after:expr) => (
         ::s_lock::SpinLock {
             before: ::std::cell::UnsafeCell { value: $before },
-            lock: ::std::cell::UnsafeCell { value: 0 },
+            lock: ::s_lock::TasLock { slock: ::std::cell::UnsafeCell { value: 0 } },
+            lockdep_id: ::std::cell::UnsafeCell { value: 0 },
+            after: ::std::cell::UnsafeCell { value: $after },
+            nocopy: ::std::kinds::marker::NoCopy,
+        }
+    )
+)
+
+// Like `spin_lock_init!`, for a `SpinLock<_, _, TicketLock>`.
+macro_rules! ticket_lock_init(
+    ($before:expr, $after:expr) => (
+        ::s_lock::SpinLock {
+            before: ::std::cell::UnsafeCell { value: $before },
+            lock: ::s_lock::TicketLock {
+                next_ticket: ::std::sync::atomic::INIT_ATOMIC_UINT,
+                now_serving: ::std::sync::atomic::INIT_ATOMIC_UINT,
+                num_abandoned: ::std::sync::atomic::INIT_ATOMIC_UINT,
+                abandoned_tickets: [::std::sync::atomic::INIT_ATOMIC_UINT,
+                                    ::std::sync::atomic::INIT_ATOMIC_UINT,
+                                    ::std::sync::atomic::INIT_ATOMIC_UINT,
+                                    ::std::sync::atomic::INIT_ATOMIC_UINT],
+            },
+            lockdep_id: ::std::cell::UnsafeCell { value: 0 },
             after: ::std::cell::UnsafeCell { value: $after },
             nocopy: ::std::kinds::marker::NoCopy,
         }
@@ -263,8 +939,18 @@ macro_rules! spin_lock_acquire(($guard:pat = $lock:expr, $body:expr) => ({
 
 #[cfg(test)]
 mod tests {
-    use super::SpinLock;
+    use super::{SpinLock, SpinLockConfig, SpinLockError, SpinLockKind, TicketLock};
+    use super::{DEFAULT_SPINS_PER_DELAY, fold_into_shared, fold_spins_per_delay, shared_estimate};
+    #[cfg(feature = "spinlock-profile")]
+    use super::{dump_spin_lock_profile, worst_spin_lock_sites};
+    #[cfg(feature = "spinlock-profile")]
+    use std::io::timer;
     use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT};
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::time::Duration;
+    #[cfg(feature = "spinlock-profile")]
+    use std::uint;
 
     #[test]
     fn test_lock() {
@@ -282,6 +968,213 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_spins_per_delay() {
+        assert_eq!(fold_spins_per_delay(100, 1000), 156);
+        assert_eq!(fold_spins_per_delay(1000, 1000), 1000);
+    }
+
+    #[test]
+    fn test_shared_spins_per_delay() {
+        // Every exiting thread folds its estimate into the real process-wide one, so use one
+        // of our own.
+        static SHARED: AtomicUint = INIT_ATOMIC_UINT;
+        assert_eq!(shared_estimate(&SHARED), DEFAULT_SPINS_PER_DELAY);
+
+        // An exiting thread folds its estimate in, and a new thread starts from the result.
+        let (tx, rx) = channel();
+        spawn(proc() {
+            fold_into_shared(&SHARED, 1000);
+            tx.send(());
+        });
+        rx.recv();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(shared_estimate(&SHARED));
+        });
+        assert_eq!(rx.recv(), 156);
+    }
+
+    #[test]
+    fn test_ticket_lock() {
+        let s_lock = SpinLock::init_ticket((), ());
+        assert!(s_lock.free())
+        spin_lock_acquire!(_guard = s_lock, {
+            assert!(!s_lock.free())
+        })
+        assert!(s_lock.free())
+        spin_lock_acquire!(_guard = s_lock, {
+            assert!(!s_lock.free())
+        })
+        assert!(s_lock.free())
+    }
+
+    #[test]
+    fn test_ticket_lock_init() {
+        static S_LOCK: SpinLock<(), uint, TicketLock> = ticket_lock_init!((), 0);
+        spin_lock_acquire!(mut guard = S_LOCK, {
+            *guard.deref_mut().1 += 1;
+        })
+        assert!(S_LOCK.free())
+    }
+
+    fn lock_contended<K>(s_lock: SpinLock<(), uint, K>) where K: SpinLockKind + Send {
+        const NUM_THREADS: uint = 8;
+        const NUM_ITERS: uint = 10000;
+
+        let s_lock = Arc::new(s_lock);
+        let (tx, rx) = channel();
+        for _ in range(0, NUM_THREADS) {
+            let s_lock = s_lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                for _ in range(0, NUM_ITERS) {
+                    spin_lock_acquire!(mut guard = s_lock, {
+                        // Deliberately non-atomic read-modify-write.
+                        let count = *guard.deref().1;
+                        *guard.deref_mut().1 = count + 1;
+                    })
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NUM_THREADS) {
+            rx.recv();
+        }
+        assert!(s_lock.free())
+        spin_lock_acquire!(guard = s_lock, {
+            assert_eq!(*guard.deref().1, NUM_THREADS * NUM_ITERS);
+        })
+    }
+
+    #[test]
+    fn test_lock_contended() {
+        lock_contended(SpinLock::init((), 0u));
+    }
+
+    #[test]
+    fn test_ticket_lock_contended() {
+        lock_contended(SpinLock::init_ticket((), 0u));
+    }
+
+    #[test]
+    fn test_lock_stuck() {
+        const CONFIG: SpinLockConfig = SpinLockConfig {
+            num_delays: 3,
+            min_delay_usec: 1000,
+            max_delay_usec: 2000,
+        };
+        let s_lock = SpinLock::init((), ());
+        // Held out of sight of the lock-order validator, which would object to the second
+        // acquisition before we could see it fail.
+        let _guard = s_lock.acquire_guard_untracked(&(file!(), line!()));
+
+        let file_line = &(file!(), line!());
+        match s_lock.acquire_with(&CONFIG, file_line) {
+            Err(SpinLockError::Stuck(stuck)) => {
+                assert_eq!(stuck.file_line, *file_line);
+                assert!(stuck.waited >= Duration::milliseconds(2));
+            },
+            result => panic!("expected a stuck spinlock, got {}", result),
+        }
+        assert!(s_lock.acquire_guard_with(&CONFIG, file_line).is_err());
+        assert!(!s_lock.free())
+    }
+
+    #[test]
+    fn test_lock_stuck_without_delays() {
+        const CONFIG: SpinLockConfig = SpinLockConfig {
+            num_delays: 0,
+            min_delay_usec: 1000,
+            max_delay_usec: 2000,
+        };
+        let s_lock = SpinLock::init((), ());
+        let _guard = s_lock.acquire_guard_untracked(&(file!(), line!()));
+        match s_lock.acquire_with(&CONFIG, &(file!(), line!())) {
+            // We gave up without sleeping at all.
+            Err(SpinLockError::Stuck(stuck)) => assert!(stuck.waited < Duration::seconds(1)),
+            result => panic!("expected a stuck spinlock, got {}", result),
+        }
+    }
+
+    #[test]
+    fn test_ticket_lock_stuck() {
+        const CONFIG: SpinLockConfig = SpinLockConfig {
+            num_delays: 3,
+            min_delay_usec: 1000,
+            max_delay_usec: 2000,
+        };
+        let s_lock = Arc::new(SpinLock::init_ticket((), ()));
+        let guard = s_lock.acquire_guard_untracked(&(file!(), line!()));
+
+        let file_line = &(file!(), line!());
+        match s_lock.acquire_with(&CONFIG, file_line) {
+            Err(SpinLockError::Stuck(stuck)) => {
+                assert_eq!(stuck.file_line, *file_line);
+                assert!(stuck.waited >= Duration::milliseconds(2));
+            },
+            result => panic!("expected a stuck spinlock, got {}", result),
+        }
+        assert!(s_lock.acquire_guard_with(&CONFIG, file_line).is_err());
+        assert!(!s_lock.free())
+
+        // The tickets we gave up are skipped, so whoever is next in line still gets the lock.
+        let (tx, rx) = channel();
+        let s_lock_ = s_lock.clone();
+        spawn(proc() {
+            spin_lock_acquire!(_guard = s_lock_, {
+                assert!(!s_lock_.free())
+            })
+            tx.send(());
+        });
+        drop(guard);
+        rx.recv();
+        assert!(s_lock.free())
+    }
+
+    #[test]
+    fn test_ticket_lock_abandoned_at_its_turn() {
+        // A ticket given up just as its turn comes is passed on by the waiter itself.
+        let s_lock = SpinLock::init_ticket((), ());
+        let ticket = s_lock.lock.next_ticket.fetch_add(1, SeqCst);
+        s_lock.lock.abandon(ticket);
+        assert!(s_lock.free())
+        assert_eq!(s_lock.lock.num_abandoned.load(SeqCst), 0);
+        spin_lock_acquire!(_guard = s_lock, {
+            assert!(!s_lock.free())
+        })
+        assert!(s_lock.free())
+    }
+
+    #[test]
+    #[cfg(feature = "spinlock-profile")]
+    fn test_profile() {
+        let s_lock = Arc::new(SpinLock::init((), ()));
+        let (tx, rx) = channel();
+        let s_lock_ = s_lock.clone();
+        spawn(proc() {
+            spin_lock_acquire!(_guard = s_lock_, {
+                tx.send(());
+                timer::sleep(Duration::milliseconds(50));
+            })
+        });
+        rx.recv();
+        static FILE_LINE: &'static (&'static str, uint) = &(file!(), line!() + 1);
+        drop(s_lock.acquire_guard(FILE_LINE));
+
+        // Other tests' call sites may be in there too.
+        let site = worst_spin_lock_sites(uint::MAX).into_iter()
+            .find( |site| site.file_line == *FILE_LINE).unwrap();
+        assert_eq!(site.contended, 1);
+        assert!(site.spins > 0);
+        assert!(site.delays > 0);
+        assert!(site.delay_time_ns > 0);
+
+        let mut dump = Vec::new();
+        dump_spin_lock_profile(&mut dump, uint::MAX).unwrap();
+        assert!(String::from_utf8(dump).unwrap().contains(file!()));
+    }
+
     #[test]
     #[cfg(feature = "long-tests")]
     fn test_lock_long() {
@@ -364,4 +1257,68 @@ mod tests {
             assert!(!s_lock.free())
         })
     }
+
+    /// Time lock/unlock pairs on a lock that `threads - 1` other threads are hammering too.
+    fn bench_contended<K>(b: &mut ::test::Bencher, s_lock: SpinLock<bool, (), K>, threads: uint)
+                          where K: SpinLockKind + Send {
+        b.bytes = 1; // One byte modified per iteration
+
+        let s_lock = Arc::new(s_lock);
+        let (tx, rx) = channel();
+        for _ in range(1, threads) {
+            let s_lock = s_lock.clone();
+            let tx = tx.clone();
+            spawn(proc() {
+                loop {
+                    spin_lock_acquire!(guard = s_lock, {
+                        if *guard.deref().0 {
+                            break
+                        }
+                    })
+                }
+                tx.send(());
+            });
+        }
+        b.iter( || {
+            spin_lock_acquire!(_guard = s_lock, {
+                assert!(!s_lock.free())
+            })
+        });
+        spin_lock_acquire!(mut guard = s_lock, {
+            *guard.deref_mut().0 = true;
+        })
+        for _ in range(1, threads) {
+            rx.recv();
+        }
+    }
+
+    #[bench]
+    fn bench_tas_contended_2(b: &mut ::test::Bencher) {
+        bench_contended(b, SpinLock::init(false, ()), 2);
+    }
+
+    #[bench]
+    fn bench_tas_contended_8(b: &mut ::test::Bencher) {
+        bench_contended(b, SpinLock::init(false, ()), 8);
+    }
+
+    #[bench]
+    fn bench_tas_contended_32(b: &mut ::test::Bencher) {
+        bench_contended(b, SpinLock::init(false, ()), 32);
+    }
+
+    #[bench]
+    fn bench_ticket_contended_2(b: &mut ::test::Bencher) {
+        bench_contended(b, SpinLock::init_ticket(false, ()), 2);
+    }
+
+    #[bench]
+    fn bench_ticket_contended_8(b: &mut ::test::Bencher) {
+        bench_contended(b, SpinLock::init_ticket(false, ()), 8);
+    }
+
+    #[bench]
+    fn bench_ticket_contended_32(b: &mut ::test::Bencher) {
+        bench_contended(b, SpinLock::init_ticket(false, ()), 32);
+    }
 }
@@ -62,7 +62,7 @@ pub type ValidTransactionIdResult = Result<NormalTransactionId, SpecialTransacti
 
 pub type TransactionIdResult = Result<NormalTransactionId, Option<SpecialTransactionId>>;
 
-#[deriving(Clone,Eq,PartialEq,Show)]
+#[deriving(Clone,Eq,Hash,PartialEq,Show)]
 #[repr(C)]
 pub struct TransactionId(u32);
 
@@ -72,6 +72,22 @@ impl TransactionId {
         TransactionId(transaction_id).to_normal()
     }
 
+    #[inline]
+    pub fn invalid() -> TransactionId {
+        TransactionId(INVALID_TRANSACTION_ID)
+    }
+
+    /// Rebuild a TransactionId from `to_u32`, e.g. after keeping it in an atomic.
+    #[inline]
+    pub fn from_u32(transaction_id: u32) -> TransactionId {
+        TransactionId(transaction_id)
+    }
+
+    #[inline]
+    pub fn to_u32(&self) -> u32 {
+        self.0
+    }
+
     #[inline]
     pub fn to_valid(&self) -> Option<ValidTransactionId> {
         match self.0 {
@@ -100,6 +116,16 @@ impl TransactionId {
         self.0 >= FIRST_NORMAL_TRANSACTION_ID
     }
 
+    /// Is this ID logically before `other`?  Normal IDs wrap around, so they are compared
+    /// modulo 2^32; special IDs come before all normal ones.
+    #[inline]
+    pub fn precedes(&self, other: &TransactionId) -> bool {
+        if !self.is_normal() || !other.is_normal() {
+            return self.0 < other.0
+        }
+        (self.0 - other.0) as i32 < 0
+    }
+
     #[inline]
     pub fn store(&mut self, xid: ValidTransactionId) {
         *self = xid.unwrap();